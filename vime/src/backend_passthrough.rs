@@ -1,28 +1,38 @@
 use std::cell::RefCell;
+use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::Arc;
 
-use xcb::x::Window;
+use xcb::x::{Atom, Window};
+use xcb::Xid as _;
+use xcb_imdkit::clib::xcb_im_feedback_t_XCB_XIM_UNDERLINE;
 use xcb_imdkit::{Ic, ImeClient, InputStyle, KeyEvent};
 
-use crate::{create_dummy_window, is_vime_message, notify, Channel, Message};
+use crate::compose::{Composed, Composer};
+use crate::{create_dummy_window, is_vime_message, notify, Channel, Commit, Message, Preedit};
 
 #[derive(Clone)]
 pub struct Context {
     conn: Arc<xcb::Connection>,
     default_screen: i32,
+    root: Window,
 
     chan: Rc<Channel>,
     win_server: Window,
     win_dummy: Window,
     current_ic: Rc<RefCell<Option<Ic>>>,
+    // the IC which shows the preedit string of the upstream IM
+    preedit_ic: Rc<RefCell<Option<Ic>>>,
 }
 
 impl Context {
     pub fn new(chan: Channel) -> Self {
         let chan = Rc::new(chan);
 
-        let (conn, default_screen) = xcb::Connection::connect(None).unwrap();
+        // NOTE: XFixes tells when an upstream IM starts or exits
+        let extensions = [xcb::Extension::XFixes];
+        let (conn, default_screen) =
+            xcb::Connection::connect_with_extensions(None, &[], &extensions).unwrap();
         let conn = Arc::new(conn);
         let screen = conn
             .get_setup()
@@ -30,6 +40,7 @@ impl Context {
             .nth(default_screen as usize)
             .unwrap();
 
+        let root = screen.root();
         let win_dummy = create_dummy_window(&conn, screen);
 
         chan.tx.send(Message::Window(win_dummy)).unwrap();
@@ -39,10 +50,12 @@ impl Context {
         Self {
             conn,
             default_screen,
+            root,
             chan,
             win_server,
             win_dummy,
             current_ic: Rc::new(RefCell::new(None)),
+            preedit_ic: Rc::new(RefCell::new(None)),
         }
     }
 }
@@ -50,31 +63,179 @@ impl Context {
 pub fn main(chan: Channel) {
     let ctx = Context::new(chan);
 
-    if std::env::var("XMODIFIERS").is_ok() {
+    if !vime_config::CONFIG.upstream_ims.is_empty() || std::env::var("XMODIFIERS").is_ok() {
         with_ime(ctx);
     } else {
         without_ime(ctx);
     }
 }
 
+/// An upstream IM which the keys are passed through to.
+struct Upstream {
+    // the name in `upstream_ims`, or None for $XMODIFIERS
+    name: Option<String>,
+    ime: Pin<Box<ImeClient>>,
+
+    // the selection owned by the XIM server (`@server=<name>`), and its owner
+    selection: Option<Atom>,
+    owner: Window,
+}
+
+impl Upstream {
+    fn new(c: &Context, name: Option<String>) -> Self {
+        let mut ime = ImeClient::new(
+            c.conn.clone(),
+            c.default_screen,
+            InputStyle::PREEDIT_CALLBACKS,
+            name.as_deref(),
+        );
+        ime.update_pos(c.win_dummy, 0, 0);
+        set_callbacks(c, &mut ime);
+
+        let selection = server_selection(c, name.as_deref());
+        let owner = match selection {
+            Some(selection) => watch_selection(c, selection),
+            None => Window::none(),
+        };
+
+        Self {
+            name,
+            ime,
+            selection,
+            owner,
+        }
+    }
+
+    /// Returns whether the XIM server is running, assuming so if it is unknown.
+    fn is_available(&self) -> bool {
+        self.selection.is_none() || !self.owner.is_none()
+    }
+
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => std::env::var("XMODIFIERS").unwrap_or_default(),
+        }
+    }
+}
+
 fn with_ime(c: Context) {
     // ImeClient::set_logger(|msg| log::trace!("Log: {}", msg));
 
-    let mut ime = ImeClient::new(
-        c.conn.clone(),
-        c.default_screen,
-        InputStyle::PREEDIT_CALLBACKS,
-        None, // derive $XMODIFIERS
-    );
+    let config = &vime_config::CONFIG;
+    let mut upstreams: Vec<Upstream> = if config.upstream_ims.is_empty() {
+        vec![Upstream::new(&c, None)]
+    } else {
+        let names = config.upstream_ims.iter().cloned();
+        names.map(|name| Upstream::new(&c, Some(name))).collect()
+    };
+
+    // the upstream IM switched with the key, and the one pinned for the current client
+    let mut active = 0;
+    let mut pinned: Option<usize> = None;
+    let mut shown = active;
+    log::info!("passthru: upstream IM: {}", upstreams[active].label());
+    show_upstream(&c, &upstreams[active].label());
+
+    // for the keys while the upstream IM is not running
+    let mut composer = Composer::new(&c.conn);
+
+    loop {
+        let event = wait_for_event(&c);
+
+        if is_vime_message(&c.conn, &event).is_some() {
+            match c.chan.rx.recv().unwrap() {
+                Message::StartPreedit(ic, upstream) => {
+                    pinned = upstream.and_then(|name| {
+                        let mut names = upstreams.iter().map(|u| u.name.as_ref());
+                        let i = names.position(|n| n == Some(&name));
+                        if i.is_none() {
+                            log::warn!("passthru: {} is not in upstream_ims", name);
+                        }
+                        i
+                    });
+                    let label = upstreams[pinned.unwrap_or(active)].label();
+                    log::debug!("passthru: start preedit ({})", label);
+                    end_preedit(&c);
+                    *c.current_ic.borrow_mut() = Some(ic);
+                }
+                Message::CancelPreedit => {
+                    log::debug!("passthru: cancel preedit");
+                    end_preedit(&c);
+                    *c.current_ic.borrow_mut() = None;
+                }
+                Message::NextUpstream => {
+                    end_preedit(&c);
+                    active = (pinned.unwrap_or(active) + 1) % upstreams.len();
+                    pinned = None;
+                    log::info!("passthru: switched to {}", upstreams[active].label());
+                }
+                _ => {}
+            }
+
+            let current = pinned.unwrap_or(active);
+            if current != shown {
+                show_upstream(&c, &upstreams[current].label());
+                shown = current;
+            }
+            if let Some(composer) = &mut composer {
+                composer.reset();
+            }
+            continue;
+        }
 
-    ime.update_pos(c.win_dummy, 0, 0);
+        if let xcb::Event::XFixes(xcb::xfixes::Event::SelectionNotify(ev)) = &event {
+            let current = pinned.unwrap_or(active);
+            for (i, upstream) in upstreams.iter_mut().enumerate() {
+                if upstream.selection != Some(ev.selection()) || upstream.owner == ev.owner() {
+                    continue;
+                }
+                if i == current {
+                    end_preedit(&c);
+                }
 
+                let label = upstream.label();
+                if ev.owner().is_none() {
+                    // NOTE: The keys are passed through directly until it comes back
+                    log::warn!("passthru: {} has exited", label);
+                    upstream.owner = Window::none();
+                } else {
+                    // NOTE: The old client can't be reused for the new server
+                    log::info!("passthru: {} has started, reconnecting", label);
+                    *upstream = Upstream::new(&c, upstream.name.take());
+                }
+            }
+            continue;
+        }
+
+        // NOTE: The key events go to the current upstream IM only,
+        // while each of them handles its own XIM messages
+        let is_key_event = matches!(
+            event,
+            xcb::Event::X(xcb::x::Event::KeyPress(_) | xcb::x::Event::KeyRelease(_))
+        );
+        if is_key_event {
+            let current = &mut upstreams[pinned.unwrap_or(active)];
+            if current.is_available() {
+                current.ime.process_event(&event);
+            } else {
+                pass_key(&c, &mut composer, event);
+            }
+        } else {
+            for upstream in upstreams.iter_mut() {
+                upstream.ime.process_event(&event);
+            }
+        }
+    }
+}
+
+fn set_callbacks(c: &Context, ime: &mut ImeClient) {
     ime.set_commit_string_cb({
         let c = c.clone();
         move |_win, input| {
             let Some(ic) = c.current_ic.borrow().clone() else { return };
 
-            let msg = Message::EditResult(ic, Some(input.to_owned()));
+            let msg = Message::EditResult(ic, Some(Commit::new(input.to_owned())));
             c.chan.tx.send(msg).unwrap();
             notify(&c.conn, c.win_dummy, c.win_server);
         }
@@ -90,37 +251,175 @@ fn with_ime(c: Context) {
         }
     });
 
-    // ime.set_preedit_draw_cb(move |_win, _info| {});
+    if !vime_config::CONFIG.passthrough_preedit {
+        return;
+    }
 
-    loop {
-        let event = c.conn.wait_for_event().unwrap();
+    ime.set_preedit_start_cb({
+        let c = c.clone();
+        move |_win| begin_preedit(&c)
+    });
 
-        if is_vime_message(&c.conn, &event).is_some() {
-            match c.chan.rx.recv().unwrap() {
-                Message::StartPreedit(ic) => {
-                    log::debug!("passthru: start preedit");
-                    *c.current_ic.borrow_mut() = Some(ic);
-                }
-                Message::CancelPreedit => {
-                    log::debug!("passthru: cancel preedit");
-                    *c.current_ic.borrow_mut() = None;
-                }
-                _ => {}
+    ime.set_preedit_draw_cb({
+        let c = c.clone();
+        move |_win, info| {
+            begin_preedit(&c);
+            let Some(ic) = c.preedit_ic.borrow().clone() else { return };
+
+            let text = info.text().to_string();
+            let mut feedback = info.feedback().to_vec();
+            if feedback.is_empty() {
+                feedback = vec![xcb_im_feedback_t_XCB_XIM_UNDERLINE; text.chars().count()];
             }
-            continue;
+            let preedit = Preedit {
+                text,
+                caret: info.caret(),
+                chg_first: info.chg_first(),
+                chg_length: info.chg_length(),
+                feedback,
+            };
+            c.chan.tx.send(Message::PreeditDraw(ic, preedit)).unwrap();
+            notify(&c.conn, c.win_dummy, c.win_server);
         }
+    });
 
-        ime.process_event(&event);
+    ime.set_preedit_done_cb({
+        let c = c.clone();
+        move |_win| end_preedit(&c)
+    });
+}
+
+/// Starts showing the preedit string in the current IC unless it already does.
+fn begin_preedit(c: &Context) {
+    let current_ic = c.current_ic.borrow().clone();
+    if *c.preedit_ic.borrow() == current_ic {
+        return;
     }
+    end_preedit(c);
+
+    let Some(ic) = current_ic else { return };
+    *c.preedit_ic.borrow_mut() = Some(ic.clone());
+    c.chan.tx.send(Message::PreeditStart(ic)).unwrap();
+    notify(&c.conn, c.win_dummy, c.win_server);
+}
+
+/// Clears the preedit string shown in the IC if any.
+fn end_preedit(c: &Context) {
+    let Some(ic) = c.preedit_ic.borrow_mut().take() else { return };
+    c.chan.tx.send(Message::PreeditDone(ic)).unwrap();
+    notify(&c.conn, c.win_dummy, c.win_server);
+}
+
+/// Returns the selection which the XIM server of the name owns.
+fn server_selection(c: &Context, name: Option<&str>) -> Option<Atom> {
+    let name = match name {
+        Some(name) => name.to_owned(),
+        None => {
+            let modifiers = std::env::var("XMODIFIERS").ok()?;
+            modifiers.strip_prefix("@im=")?.to_owned()
+        }
+    };
+
+    let selection = format!("@server={}", name);
+    let cookie = c.conn.send_request(&xcb::x::InternAtom {
+        only_if_exists: false,
+        name: selection.as_bytes(),
+    });
+    Some(c.conn.wait_for_reply(cookie).ok()?.atom())
+}
+
+/// Asks for XFixes SelectionNotify on changes of the owner of the selection,
+/// and returns the current owner.
+fn watch_selection(c: &Context, selection: Atom) -> Window {
+    let cookie = c.conn.send_request(&xcb::xfixes::QueryVersion {
+        client_major_version: 5,
+        client_minor_version: 0,
+    });
+    if c.conn.wait_for_reply(cookie).is_ok() {
+        let event_mask = xcb::xfixes::SelectionEventMask::SET_SELECTION_OWNER
+            | xcb::xfixes::SelectionEventMask::SELECTION_WINDOW_DESTROY
+            | xcb::xfixes::SelectionEventMask::SELECTION_CLIENT_CLOSE;
+        c.conn.send_request(&xcb::xfixes::SelectSelectionInput {
+            window: c.win_dummy,
+            selection,
+            event_mask,
+        });
+    } else {
+        log::warn!("passthru: XFixes is not available");
+    }
+
+    let request = xcb::x::GetSelectionOwner { selection };
+    let cookie = c.conn.send_request(&request);
+    match c.conn.wait_for_reply(cookie) {
+        Ok(reply) => reply.owner(),
+        Err(_) => Window::none(),
+    }
+}
+
+/// Waits for an event, ignoring errors such as BadWindow for the upstream IM which has exited.
+fn wait_for_event(c: &Context) -> xcb::Event {
+    loop {
+        match c.conn.wait_for_event() {
+            Ok(event) => return event,
+            Err(xcb::Error::Protocol(err)) => log::debug!("passthru: {:?}", err),
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
+
+/// Forwards the key event to the client without an upstream IM.
+fn pass_key(c: &Context, composer: &mut Option<Composer>, event: xcb::Event) {
+    let Some(ic) = c.current_ic.borrow().clone() else { return };
+
+    let xcb::Event::X(xev) = event else { return };
+    let Some(key_event) = KeyEvent::from_xevent(xev) else { return };
+
+    let composed = composer.as_mut().map(|composer| composer.feed(&key_event));
+    let msg = match composed {
+        Some(Composed::Consumed) => return,
+        Some(Composed::Text(text)) => {
+            log::debug!("passthru: composed");
+            Message::EditResult(ic, Some(Commit::new(text)))
+        }
+        Some(Composed::Forward) | None => Message::ForwardEvent(ic, key_event),
+    };
+    c.chan.tx.send(msg).unwrap();
+    notify(&c.conn, c.win_dummy, c.win_server);
+}
+
+/// Shows the upstream IM in use as `_VIME_UPSTREAM_IM` on the root window for status bars.
+fn show_upstream(c: &Context, label: &str) {
+    let cookie = c.conn.send_request(&xcb::x::InternAtom {
+        only_if_exists: false,
+        name: b"_VIME_UPSTREAM_IM",
+    });
+    let property = c.conn.wait_for_reply(cookie).unwrap().atom();
+    let cookie = c.conn.send_request(&xcb::x::InternAtom {
+        only_if_exists: false,
+        name: b"UTF8_STRING",
+    });
+    let utf8_string = c.conn.wait_for_reply(cookie).unwrap().atom();
+
+    c.conn.send_request(&xcb::x::ChangeProperty {
+        mode: xcb::x::PropMode::Replace,
+        window: c.root,
+        property,
+        r#type: utf8_string,
+        data: label.as_bytes(),
+    });
+    c.conn.flush().unwrap();
 }
 
 fn without_ime(c: Context) {
+    // NOTE: Without an IM, the clients rely on vime for compose sequences and dead keys
+    let mut composer = Composer::new(&c.conn);
+
     loop {
-        let event = c.conn.wait_for_event().unwrap();
+        let event = wait_for_event(&c);
 
         if is_vime_message(&c.conn, &event).is_some() {
             match c.chan.rx.recv().unwrap() {
-                Message::StartPreedit(ic) => {
+                Message::StartPreedit(ic, _) => {
                     *c.current_ic.borrow_mut() = Some(ic);
                 }
                 Message::CancelPreedit => {
@@ -128,16 +427,12 @@ fn without_ime(c: Context) {
                 }
                 _ => {}
             }
+            if let Some(composer) = &mut composer {
+                composer.reset();
+            }
             continue;
         }
 
-        let Some(ic) = c.current_ic.borrow().clone() else { continue };
-
-        let xcb::Event::X(xev) = event else { continue };
-        let Some(key_event) = KeyEvent::from_xevent(xev) else { continue };
-
-        let msg = Message::ForwardEvent(ic, key_event);
-        c.chan.tx.send(msg).unwrap();
-        notify(&c.conn, c.win_dummy, c.win_server);
+        pass_key(&c, &mut composer, event);
     }
 }
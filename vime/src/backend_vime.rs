@@ -1,91 +1,483 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use toyterm::glium::glutin::dpi::PhysicalSize;
+use toyterm::glium::glutin::event::{Event, WindowEvent};
+use toyterm::glium::glutin::event_loop::EventLoopWindowTarget;
 use toyterm::{glium, window, TOYTERM_CONFIG};
-use xcb::XidNew as _;
+use unicode_segmentation::UnicodeSegmentation as _;
+use vime_config::{IdleTimeoutAction, Style};
+use xcb::{Xid as _, XidNew as _};
 use xcb_imdkit::Ic;
 
-use crate::{notify, Channel, Message};
+use crate::status_line::StatusLine;
+use crate::{draft, notify, Channel, Client, Commit, Message, SessionMode};
 
-pub fn main(chan: Channel) {
-    // Make sure that configuration errors are detected earlier
-    lazy_static::initialize(&TOYTERM_CONFIG);
+/// Interval to check the buffer file for the line-by-line mode and the status line.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    let event_loop = glium::glutin::event_loop::EventLoop::new();
+/// How far a new session is from being shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Startup {
+    /// The window is hidden until the editor draws something.
+    WaitingForOutput,
+    /// The window has been shown, and waits for Expose from the X server.
+    WaitingForPaint,
+    Ready,
+}
 
-    let display = {
-        let title = "vime";
+/// A vime session running for an IC.
+struct Session {
+    // None for a scratchpad, whose text goes to the clipboard
+    ic: Option<Ic>,
+    mode: SessionMode,
 
-        use glium::glutin::platform::unix::WindowBuilderExtUnix as _;
-        use glium::glutin::{window::WindowBuilder, ContextBuilder};
+    term: window::TerminalWindow,
+    win: xcb::x::Window,
+    term_size: window::TerminalSize,
 
-        let win_builder = WindowBuilder::new()
-            .with_title(title)
-            .with_resizable(true)
-            .with_override_redirect(true)
-            .with_always_on_top(true);
+    status_line: Option<StatusLine>,
+    client: Client,
+    style: Style,
+    // whether a draft has been reopened
+    has_draft: bool,
+    // the number of characters in the buffer file
+    chars: usize,
 
-        let ctx_builder = ContextBuilder::new().with_vsync(true).with_srgb(true);
-        glium::Display::new(win_builder, ctx_builder, &event_loop).expect("display new")
-    };
+    // the number of lines already committed in the line-by-line mode
+    sent_lines: usize,
+    next_poll: Instant,
 
-    let mut term = window::TerminalWindow::new(display, None);
+    startup: Startup,
+    // when the session was hidden by focus-out
+    hidden_since: Option<Instant>,
+    is_pinned: bool,
+}
+
+impl Session {
+    /// Starts a new editor for the IC in the terminal window.
+    /// The text is opened instead of the draft if given.
+    fn start(
+        conn: &xcb::Connection,
+        mut term: window::TerminalWindow,
+        ic: Option<Ic>,
+        mode: SessionMode,
+        client: Client,
+        text: Option<String>,
+        notices: &mut Vec<String>,
+    ) -> Self {
+        let win = x_window(&term);
+
+        term.close_pty();
+        let buffer_path = buffer_path(win);
+        let cursor_path = cursor_path(win);
+        remove_session_files(win);
+
+        // Open the given text, or reopen the text which could not be committed previously
+        let mut has_draft = false;
+        if let Some(text) = text {
+            if let Err(err) = draft::write_new_private(&buffer_path, &text) {
+                log::error!("failed to open the text: {}", err);
+            }
+        } else if mode == SessionMode::Normal {
+            if let Some(text) = draft::take(&client.class) {
+                match draft::write_new_private(&buffer_path, &text) {
+                    Ok(()) => {
+                        notices.push("vime: reopened the draft".to_owned());
+                        has_draft = true;
+                    }
+                    Err(err) => log::error!("failed to reopen a draft: {}", err),
+                }
+            }
+        }
+
+        // Let the editor know the files, the mode and the notices (see config_example/vimrc)
+        let mut env = vec![
+            ("VIME_BUFFER_FILE".to_owned(), buffer_path.into_os_string()),
+            ("VIME_CURSOR_FILE".to_owned(), cursor_path.into_os_string()),
+            ("VIME_MODE".to_owned(), mode.as_str().into()),
+        ];
+        if !notices.is_empty() {
+            env.push(("VIME_NOTICE".to_owned(), notices.join(" / ").into()));
+            notices.clear();
+        }
+
+        // NOTE: The variables are given to the shell only, since other sessions run concurrently
+        term.set_child_env(env);
+        term.reset_pty();
+
+        let style = vime_config::CONFIG.style(&client.class);
+        set_frame(conn, win, &style);
+
+        // The IM server forwards Expose of the window (see Session::on_exposed)
+        conn.send_request(&xcb::x::ChangeWindowAttributes {
+            window: win,
+            value_list: &[xcb::x::Cw::EventMask(xcb::x::EventMask::EXPOSURE)],
+        });
+
+        let status_line = vime_config::CONFIG
+            .status_line
+            .then(|| StatusLine::new(conn, win));
+
+        let mut session = Self {
+            ic,
+            mode,
+            term,
+            win,
+            term_size: default_terminal_size(),
+            status_line,
+            client,
+            style,
+            has_draft,
+            chars: 0,
+            sent_lines: 0,
+            next_poll: Instant::now(),
+            startup: Startup::WaitingForOutput,
+            hidden_since: None,
+            is_pinned: false,
+        };
+        session.resize(conn, session.term_size);
+        session
+    }
 
-    {
-        // Invisible by default
-        term.hide();
+    /// Resizes the window to fit the terminal with the padding, and the status line.
+    fn resize(&mut self, conn: &xcb::Connection, size: window::TerminalSize) {
+        self.term_size = size;
 
-        let rows = vime_config::CONFIG.default_rows;
-        let cols = vime_config::CONFIG.default_columns;
-        term.resize_with_terminal_size(window::TerminalSize { rows, cols });
+        let padding = self.style.padding;
+        let margin = self.status_line.as_ref().map_or(0, |s| s.height() as u32);
+        let (w, h) = self
+            .term
+            .resize_with_terminal_size_and_margin(size, padding, margin);
+        let (width, height) = (w + padding * 2, h + padding * 2 + margin);
+
+        // NOTE: winit only queues the resize in Xlib. Resize it on this connection as well,
+        // and wait for the X server, so that the IM server sees the new size
+        // when it places the window (e.g. after the font is scaled for the DPI).
+        let result = conn.send_and_check_request(&xcb::x::ConfigureWindow {
+            window: self.win,
+            value_list: &[
+                xcb::x::ConfigWindow::Width(width),
+                xcb::x::ConfigWindow::Height(height),
+            ],
+        });
+        if let Err(err) = result {
+            log::warn!("vime: failed to resize the window: {:?}", err);
+        }
+
+        if let Some(status_line) = &self.status_line {
+            status_line.place(conn, h + padding * 2, width);
+        }
+    }
+
+    /// Reapplies the padding and the margin for the status line after the window is resized,
+    /// since toyterm makes the terminal fill the whole window on `WindowEvent::Resized`.
+    fn on_resized(&mut self, conn: &xcb::Connection, size: PhysicalSize<u32>) {
+        let padding = self.style.padding;
+        let margin = self.status_line.as_ref().map_or(0, |s| s.height() as u32);
+        let viewport = window::Viewport {
+            x: padding,
+            y: padding,
+            w: size.width.saturating_sub(padding * 2),
+            h: size.height.saturating_sub(padding * 2 + margin),
+        };
+        self.term.set_viewport(viewport);
+
+        if let Some(status_line) = &self.status_line {
+            status_line.place(conn, viewport.h + padding * 2, size.width);
+        }
+    }
+
+    fn draw_status_line(&self, conn: &xcb::Connection) {
+        let Some(status_line) = &self.status_line else { return };
+
+        let mode = match self.ic {
+            Some(_) => self.mode.as_str(),
+            None => "scratchpad",
+        };
+        let client = match self.ic {
+            Some(_) => format!("{}: {}", self.client.class, self.client.title),
+            None => "clipboard".to_owned(),
+        };
+        let draft = if self.has_draft { " (draft)" } else { "" };
+
+        let text = format!("-> {}  [{}]{}  {} chars", client, mode, draft, self.chars);
+        status_line.draw(conn, &text);
+    }
+
+    /// Shows the window once the editor has drawn something.
+    ///
+    /// NOTE: toyterm requests a redraw of the hidden window when the editor writes to the terminal.
+    fn on_redraw(&mut self) {
+        if self.startup == Startup::WaitingForOutput {
+            self.startup = Startup::WaitingForPaint;
+            if self.hidden_since.is_none() {
+                self.term.show();
+            }
+        }
     }
 
-    let vime_win = unsafe { xcb::x::Window::new(term.window_id()) };
+    /// Advances the startup on Expose of the window, and returns true when it gets ready.
+    /// The X server sends Expose only after the window is mapped.
+    fn on_exposed(&mut self) -> bool {
+        if self.startup == Startup::WaitingForPaint && self.hidden_since.is_none() {
+            self.startup = Startup::Ready;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Called when the window is shown and painted.
+    fn on_ready(&self, conn: &xcb::Connection) {
+        // NOTE: No client forwards the key events to a scratchpad
+        if self.ic.is_none() {
+            set_input_focus(conn, self.win);
+        }
+    }
+
+    /// Determines the text to be committed after the editor exits.
+    fn result(
+        &self,
+        buffer: Option<(String, bool)>,
+        status: i32,
+        notices: &mut Vec<String>,
+    ) -> Option<Commit> {
+        match buffer {
+            Some((text, _)) if status != 0 => {
+                log::warn!("vime: the editor exited with status {}", status);
+                // The lines already committed in the line-by-line mode are not saved again
+                let text = self.uncommitted(text);
+                if !text.is_empty() {
+                    let reason = format!("the editor exited with status {}", status);
+                    notices.push(save_draft(&self.client.class, &text, &reason));
+                }
+                None
+            }
+
+            Some((text, is_lossy)) => {
+                if is_lossy {
+                    notices.push(
+                        "vime: invalid UTF-8 sequences in the previous text \
+                         were replaced with U+FFFD"
+                            .to_owned(),
+                    );
+                }
+
+                if self.mode == SessionMode::Line {
+                    // Commit only the rest of the lines
+                    Some(Commit::new(self.uncommitted(text)))
+                } else {
+                    let mut commit = Commit::new(text);
+                    if vime_config::CONFIG.restore_cursor {
+                        commit.caret_back = read_caret_back(&cursor_path(self.win), &commit.text);
+                    }
+                    Some(commit)
+                }
+            }
+
+            None => None,
+        }
+    }
+
+    /// Returns the part of the text which has not been committed yet.
+    fn uncommitted(&self, text: String) -> String {
+        if self.mode == SessionMode::Line {
+            text.split_inclusive('\n').skip(self.sent_lines).collect()
+        } else {
+            text
+        }
+    }
+
+    /// Closes the editor, and returns the terminal window to be reused.
+    fn close(mut self, conn: &xcb::Connection) -> window::TerminalWindow {
+        self.term.hide();
+        self.term.close_pty();
+        if self.is_pinned {
+            show_pinned(conn, self.win, false, &self.style);
+        }
+        if let Some(status_line) = self.status_line {
+            status_line.destroy(conn);
+        }
+        remove_session_files(self.win);
+        self.term
+    }
+}
+
+pub fn main(chan: Channel) {
+    // Make sure that configuration errors are detected earlier
+    lazy_static::initialize(&TOYTERM_CONFIG);
+
+    // The files shared with the editor must not be readable or replaceable by other users
+    if let Err(err) = draft::create_runtime_dir() {
+        log::error!("vime: failed to create the runtime directory: {}", err);
+        log::error!("vime: only the passthrough is available");
+        run_without_window(chan);
+        return;
+    }
+
+    let event_loop = glium::glutin::event_loop::EventLoop::new();
+
+    // NOTE: The first window is never destroyed (but reused for sessions),
+    // so that the IM server can identify the messages from this backend with it.
+    let term = create_terminal_window(&event_loop);
+
+    let vime_win = x_window(&term);
     chan.tx.send(Message::Window(vime_win)).unwrap();
 
     let Ok(Message::Window(server_win)) = chan.rx.recv() else { panic!("bug") };
     let Ok(Message::Conn(conn)) = chan.rx.recv() else { panic!("bug") };
 
-    // Set border width
-    conn.send_request(&xcb::x::ConfigureWindow {
-        window: vime_win,
-        value_list: &[xcb::x::ConfigWindow::BorderWidth(1)],
-    });
-    conn.flush().unwrap();
+    let mut sessions: Vec<Session> = Vec::new();
+    // terminal windows which are not used by any session
+    let mut idle_terms = vec![term];
 
-    let current_ic: Rc<RefCell<Option<Ic>>> = Rc::new(RefCell::new(None));
+    // messages shown in the next session
+    let mut notices: Vec<String> = Vec::new();
 
-    event_loop.run(move |event, _, control_flow| {
+    event_loop.run(move |event, target, control_flow| {
         let Some(event) = event.to_static() else { return };
 
         loop {
             match chan.rx.try_recv() {
-                Ok(Message::StartPreedit(ic)) => {
-                    if current_ic.borrow().as_ref() == Some(&ic) {
+                Ok(msg @ (Message::StartSession(..) | Message::StartScratchpad)) => {
+                    let (ic, mode, client, text) = match msg {
+                        Message::StartSession(ic, mode, client, text) => {
+                            (Some(ic), mode, client, text)
+                        }
+                        _ => (None, SessionMode::Normal, Client::default(), None),
+                    };
+
+                    let pos = sessions.iter().position(|s| s.ic == ic);
+                    if pos.filter(|&i| sessions[i].mode == mode).is_some() {
                         log::debug!("vime: restart");
                     } else {
-                        log::debug!("vime: start preedit, set new ic");
-                        *current_ic.borrow_mut() = Some(ic);
+                        log::debug!("vime: start {} session", mode.as_str());
 
-                        term.close_pty();
-                        let _ = std::fs::remove_file("/tmp/vime_buffer.txt");
-                        term.reset_pty();
+                        if let Some(i) = pos {
+                            idle_terms.push(sessions.remove(i).close(&conn));
+                        }
+
+                        let term = idle_terms.pop().unwrap_or_else(|| {
+                            log::debug!("vime: create a new window");
+                            create_terminal_window(target)
+                        });
+
+                        let session = Session::start(
+                            &conn,
+                            term,
+                            ic.clone(),
+                            mode,
+                            client,
+                            text,
+                            &mut notices,
+                        );
+
+                        let msg = match &ic {
+                            Some(ic) => Message::SessionWindow(ic.clone(), session.win),
+                            None => Message::ScratchpadWindow(session.win),
+                        };
+                        chan.tx.send(msg).unwrap();
+                        notify(&conn, vime_win, server_win);
+
+                        sessions.push(session);
                     }
 
-                    // HACK
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                    term.show();
+                    // A new session is shown when the editor draws (see Session::on_redraw)
+                    let session = sessions.iter_mut().find(|s| s.ic == ic).unwrap();
+                    session.hidden_since = None;
+                    match session.startup {
+                        Startup::WaitingForOutput => {}
+                        Startup::WaitingForPaint => session.term.show(),
+                        Startup::Ready => {
+                            session.term.show();
+                            session.draw_status_line(&conn);
+                            session.on_ready(&conn);
+                        }
+                    }
                 }
 
-                Ok(Message::FocusOut) => {
+                Ok(Message::FocusOut(ic)) => {
                     log::debug!("vime: focus out");
-                    term.hide();
+                    let session = sessions.iter_mut().find(|s| s.ic.as_ref() == Some(&ic));
+                    if let Some(session) = session {
+                        session.term.hide();
+                        session.hidden_since = Some(Instant::now());
+                        if session.startup == Startup::Ready {
+                            session.startup = Startup::WaitingForPaint;
+                        }
+                    }
                 }
 
-                Ok(Message::CancelPreedit) => {
-                    log::debug!("vime: cancel preedit");
-                    term.hide();
-                    term.close_pty();
+                Ok(Message::Exposed(window)) => {
+                    let session = sessions.iter_mut().find(|s| s.win == window);
+                    if let Some(session) = session {
+                        if session.on_exposed() {
+                            log::debug!("vime: session ready");
+                            session.draw_status_line(&conn);
+                            session.on_ready(&conn);
+                        }
+                    }
+                }
+
+                Ok(Message::Pin(ic, pinned)) => {
+                    log::debug!("vime: pinned={}", pinned);
+                    let session = sessions.iter_mut().find(|s| s.ic.as_ref() == Some(&ic));
+                    if let Some(session) = session {
+                        session.is_pinned = pinned;
+                        show_pinned(&conn, session.win, pinned, &session.style);
+                    }
+                }
+
+                Ok(Message::Input(ic, bytes)) => {
+                    let session = sessions.iter_mut().find(|s| s.ic.as_ref() == Some(&ic));
+                    if let Some(session) = session {
+                        session.term.write_input(&bytes);
+                    }
+                }
+
+                Ok(Message::FontSize(window, font_size)) => {
+                    log::debug!("vime: font size {}", font_size);
+                    let session = sessions.iter_mut().find(|s| s.win == window);
+                    if let Some(session) = session {
+                        // Keep the number of rows and columns
+                        session.term.set_font_size(font_size);
+                        session.resize(&conn, session.term_size);
+                        let msg = match &session.ic {
+                            Some(ic) => Message::Resized(ic.clone()),
+                            None => Message::ScratchpadWindow(session.win),
+                        };
+                        chan.tx.send(msg).unwrap();
+                        notify(&conn, vime_win, server_win);
+                    }
+                }
+
+                Ok(Message::CancelSession(ic)) => {
+                    log::debug!("vime: cancel session");
+                    if let Some(i) = sessions.iter().position(|s| s.ic.as_ref() == Some(&ic)) {
+                        idle_terms.push(sessions.remove(i).close(&conn));
+                    }
+                }
+
+                Ok(Message::DropSession(ic)) => {
+                    log::debug!("vime: drop session");
+                    if let Some(i) = sessions.iter().position(|s| s.ic.as_ref() == Some(&ic)) {
+                        let session = sessions.remove(i);
+                        let (text, _) = read_buffer(&buffer_path(session.win)).unwrap_or_default();
+                        let text = session.uncommitted(text);
+                        if !text.is_empty() {
+                            let reason = "the input context has been destroyed";
+                            notices.push(save_draft(&session.client.class, &text, reason));
+                        }
+                        idle_terms.push(session.close(&conn));
+                    }
+                }
+
+                Ok(Message::CancelScratchpad) => {
+                    log::debug!("vime: cancel scratchpad");
+                    if let Some(i) = sessions.iter().position(|s| s.ic.is_none()) {
+                        idle_terms.push(sessions.remove(i).close(&conn));
+                    }
                 }
 
                 Err(std::sync::mpsc::TryRecvError::Empty) => {
@@ -95,25 +487,475 @@ pub fn main(chan: Channel) {
             }
         }
 
-        term.on_event(&event, control_flow);
-
         use glium::glutin::event_loop::ControlFlow;
-        if *control_flow == ControlFlow::Exit {
-            *control_flow = ControlFlow::default();
 
-            let edit_result = std::fs::read_to_string("/tmp/vime_buffer.txt").ok();
+        // Window events are dispatched to the terminal of the window, and others to all
+        let event_window = match &event {
+            Event::WindowEvent { window_id, .. } | Event::RedrawRequested(window_id) => {
+                Some(u64::from(*window_id) as u32)
+            }
+            _ => None,
+        };
+        let is_for = |term: &window::TerminalWindow| {
+            event_window.is_none() || event_window == Some(term.window_id())
+        };
+
+        for term in idle_terms.iter_mut().filter(|term| is_for(term)) {
+            term.on_event(&event, &mut ControlFlow::default());
+        }
+
+        *control_flow = ControlFlow::Wait;
+
+        let send = |msg: Message| {
+            chan.tx.send(msg).unwrap();
+            notify(&conn, vime_win, server_win);
+        };
+
+        let mut i = 0;
+        while i < sessions.len() {
+            let session = &mut sessions[i];
+            let mut is_closed = false;
+
+            if is_for(&session.term) {
+                let mut flow = ControlFlow::default();
+                session.term.on_event(&event, &mut flow);
+
+                if let Event::WindowEvent {
+                    event: WindowEvent::Resized(size),
+                    ..
+                } = &event
+                {
+                    session.on_resized(&conn, *size);
+                }
+
+                if flow == ControlFlow::Exit {
+                    let buffer = read_buffer(&buffer_path(session.win));
+                    if let Some(status) = session.term.reset_pty() {
+                        let result = session.result(buffer, status, &mut notices);
+                        match &session.ic {
+                            Some(ic) => send(Message::EditResult(ic.clone(), result)),
+                            None => send(Message::ScratchpadResult(result)),
+                        }
+                        is_closed = true;
+                    }
+                } else {
+                    merge_control_flow(control_flow, flow);
+                }
+
+                // NOTE: the status line is exposed together with the vime window
+                if let Event::RedrawRequested(_) = &event {
+                    session.on_redraw();
+                    session.draw_status_line(&conn);
+                }
+
+                // Since the window is override-redirect, take the input focus by ourselves
+                // so that a pinned session can be edited directly.
+                if let Event::WindowEvent { event, .. } = &event {
+                    use glium::glutin::event::{ElementState, WindowEvent};
+                    let is_clicked = matches!(
+                        event,
+                        WindowEvent::MouseInput { state, .. } if *state == ElementState::Pressed
+                    );
+
+                    if (session.is_pinned || session.ic.is_none()) && is_clicked {
+                        set_input_focus(&conn, session.win);
+                    }
+                }
+
+                if let Some(size) = session.term.take_resize_request() {
+                    if vime_config::CONFIG.accept_resize_request {
+                        let size = clamp_terminal_size(size, &vime_config::CONFIG);
+                        log::debug!("vime: resize to {}x{}", size.cols, size.rows);
+
+                        if size != session.term_size {
+                            session.resize(&conn, size);
+                            if let Some(ic) = &session.ic {
+                                send(Message::Resized(ic.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+
+            // NOTE: a scratchpad is never hidden
+            if let (Some(since), Some(timeout)) = (session.hidden_since, idle_timeout()) {
+                if since + timeout <= Instant::now() {
+                    log::info!("vime: the session reached the idle timeout");
+                    notices.push(close_idle_session(session));
+                    if let Some(ic) = &session.ic {
+                        send(Message::EditResult(ic.clone(), None));
+                    }
+                    is_closed = true;
+                } else {
+                    wait_until(control_flow, since + timeout);
+                }
+            }
+
+            let is_line_mode = session.mode == SessionMode::Line && session.ic.is_some();
+            if !is_closed && (is_line_mode || session.status_line.is_some()) {
+                if session.next_poll <= Instant::now() {
+                    session.next_poll = Instant::now() + POLL_INTERVAL;
+
+                    let (buffer, _) = read_buffer(&buffer_path(session.win)).unwrap_or_default();
 
-            if let Some(status) = term.reset_pty() {
-                let ic = current_ic.borrow().clone().unwrap();
-                let msg = Message::EditResult(ic, if status == 0 { edit_result } else { None });
-                chan.tx.send(msg).unwrap();
-                notify(&conn, vime_win, server_win);
+                    if let Some(ic) = session.ic.as_ref().filter(|_| is_line_mode) {
+                        let lines: Vec<&str> = buffer
+                            .split_inclusive('\n')
+                            .filter_map(|line| line.strip_suffix('\n'))
+                            .collect();
 
-                term.hide();
+                        for line in lines.iter().skip(session.sent_lines) {
+                            log::debug!("vime: commit a line");
+                            send(Message::CommitLine(ic.clone(), line.to_string()));
+                        }
+                        session.sent_lines = session.sent_lines.max(lines.len());
+                    }
+
+                    let chars = buffer.chars().count();
+                    if chars != session.chars {
+                        session.chars = chars;
+                        session.draw_status_line(&conn);
+                    }
+                }
 
-                log::debug!("vime: reset ic");
-                *current_ic.borrow_mut() = None;
+                wait_until(control_flow, session.next_poll);
+            }
+
+            if is_closed {
+                idle_terms.push(sessions.remove(i).close(&conn));
+            } else {
+                i += 1;
             }
         }
     });
 }
+
+/// Returns the path to a file in the runtime directory, which `main` has created.
+fn runtime_path(name: &str) -> PathBuf {
+    let mut path = vime_config::runtime_dir().unwrap_or_default();
+    path.push(name);
+    path
+}
+
+/// Deletes the files of the session in the window.
+fn remove_session_files(win: xcb::x::Window) {
+    let _ = std::fs::remove_file(buffer_path(win));
+    let _ = std::fs::remove_file(cursor_path(win));
+}
+
+/// The file which the editor of the session in the window edits.
+fn buffer_path(win: xcb::x::Window) -> PathBuf {
+    runtime_path(&format!("buffer_{}.txt", win.resource_id()))
+}
+
+/// A file to which the editor writes the cursor position ("<line> <column>") on exit.
+fn cursor_path(win: xcb::x::Window) -> PathBuf {
+    runtime_path(&format!("cursor_{}.txt", win.resource_id()))
+}
+
+/// Keeps the IM server running without the vime backend.
+fn run_without_window(chan: Channel) {
+    // NOTE: The IM server disables the triggers when it receives no window
+    let msg = Message::Window(xcb::x::Window::none());
+    chan.tx.send(msg).unwrap();
+
+    // Keep the channel open, and discard the messages
+    while chan.rx.recv().is_ok() {}
+}
+
+/// Creates an invisible terminal window.
+fn create_terminal_window(target: &EventLoopWindowTarget<()>) -> window::TerminalWindow {
+    let display = {
+        let title = "vime";
+
+        use glium::glutin::platform::unix::{WindowBuilderExtUnix as _, XWindowType};
+        use glium::glutin::{window::WindowBuilder, ContextBuilder};
+
+        // NOTE: _NET_WM_PID is set by winit
+        let override_redirect = vime_config::CONFIG.override_redirect;
+        let window_type = if override_redirect {
+            XWindowType::DropdownMenu
+        } else {
+            XWindowType::Utility
+        };
+
+        let win_builder = WindowBuilder::new()
+            .with_title(title)
+            .with_name("Vime", "vime")
+            .with_x11_window_type(vec![window_type])
+            .with_resizable(true)
+            .with_override_redirect(override_redirect)
+            .with_always_on_top(true);
+
+        let ctx_builder = ContextBuilder::new().with_vsync(true).with_srgb(true);
+        let gl_window = ctx_builder
+            .build_windowed(win_builder, target)
+            .expect("build windowed");
+        glium::Display::from_gl_window(gl_window).expect("display new")
+    };
+
+    let mut term = window::TerminalWindow::new(display, None);
+
+    // Invisible by default
+    term.hide();
+    term.resize_with_terminal_size(default_terminal_size());
+
+    term
+}
+
+fn x_window(term: &window::TerminalWindow) -> xcb::x::Window {
+    unsafe { xcb::x::Window::new(term.window_id()) }
+}
+
+fn set_input_focus(conn: &xcb::Connection, win: xcb::x::Window) {
+    conn.send_request(&xcb::x::SetInputFocus {
+        revert_to: xcb::x::InputFocus::PointerRoot,
+        focus: win,
+        time: xcb::x::CURRENT_TIME,
+    });
+    conn.flush().unwrap();
+}
+
+/// Sets the border and the opacity of the vime window.
+fn set_frame(conn: &xcb::Connection, vime_win: xcb::x::Window, style: &Style) {
+    conn.send_request(&xcb::x::ConfigureWindow {
+        window: vime_win,
+        value_list: &[xcb::x::ConfigWindow::BorderWidth(style.border_width)],
+    });
+    conn.send_request(&xcb::x::ChangeWindowAttributes {
+        window: vime_win,
+        value_list: &[xcb::x::Cw::BorderPixel(0xFF000000 | style.border_color)],
+    });
+
+    let cookie = conn.send_request(&xcb::x::InternAtom {
+        only_if_exists: false,
+        name: b"_NET_WM_WINDOW_OPACITY",
+    });
+    let opacity_atom = conn.wait_for_reply(cookie).unwrap().atom();
+    if style.opacity < 1.0 {
+        let opacity = (style.opacity * u32::MAX as f64) as u32;
+        conn.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: vime_win,
+            property: opacity_atom,
+            r#type: xcb::x::ATOM_CARDINAL,
+            data: &[opacity],
+        });
+    } else {
+        // Fully opaque without the property
+        conn.send_request(&xcb::x::DeleteProperty {
+            window: vime_win,
+            property: opacity_atom,
+        });
+    }
+    conn.flush().unwrap();
+}
+
+/// Indicates whether the session is pinned with the border color.
+fn show_pinned(conn: &xcb::Connection, vime_win: xcb::x::Window, pinned: bool, style: &Style) {
+    let color = if pinned {
+        vime_config::CONFIG.pinned_border_color
+    } else {
+        style.border_color
+    };
+
+    conn.send_request(&xcb::x::ChangeWindowAttributes {
+        window: vime_win,
+        value_list: &[xcb::x::Cw::BorderPixel(0xFF000000 | color)],
+    });
+    conn.flush().unwrap();
+}
+
+fn idle_timeout() -> Option<Duration> {
+    match vime_config::CONFIG.idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Saves or discards the text of an idle session according to the configuration,
+/// and returns a message to tell the user what happened.
+fn close_idle_session(session: &Session) -> String {
+    match vime_config::CONFIG.idle_timeout_action {
+        IdleTimeoutAction::Cancel => "vime: the idle session has been cancelled".to_owned(),
+
+        IdleTimeoutAction::Save => {
+            let (text, _) = read_buffer(&buffer_path(session.win)).unwrap_or_default();
+            let text = session.uncommitted(text);
+            if text.is_empty() {
+                return "vime: the idle session has been cancelled (empty)".to_owned();
+            }
+            let reason = "the session reached the idle timeout";
+            save_draft(&session.client.class, &text, reason)
+        }
+    }
+}
+
+/// Saves the text as a draft, and returns a message to tell the user about it.
+fn save_draft(class: &str, text: &str, reason: &str) -> String {
+    match draft::save(class, text) {
+        Ok(path) => {
+            let path = path.display();
+            format!("vime: {}, the text has been saved: {}", reason, path)
+        }
+        Err(err) => {
+            log::error!("failed to save a draft: {}", err);
+            format!("vime: {}, failed to save the text: {}", reason, err)
+        }
+    }
+}
+
+/// Reads the cursor position written by the editor, and returns the number of grapheme clusters
+/// after the cursor, i.e. how many times the caret should go back after committing the text.
+/// They are counted as most text fields move the caret by Left.
+///
+/// The caret is placed after the grapheme cluster under the cursor,
+/// so that it stays at the end of the text if the cursor is on the last one.
+fn read_caret_back(cursor_path: &Path, text: &str) -> usize {
+    let Ok(cursor) = std::fs::read_to_string(cursor_path) else { return 0 };
+    let mut cursor = cursor.split_whitespace().map(|n| n.parse::<usize>());
+    let (Some(Ok(line)), Some(Ok(col))) = (cursor.next(), cursor.next()) else {
+        log::warn!("vime: invalid cursor position");
+        return 0;
+    };
+
+    // NOTE: both are 1-origin, and the column is in bytes
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(|l| l.len())
+        .sum();
+    let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+
+    let col = col.saturating_sub(1).min(line_text.len());
+    let Some(line_after) = line_text.get(col..) else { return 0 };
+    let offset = match line_after.graphemes(true).next() {
+        Some(grapheme) => line_start + col + grapheme.len(),
+        None => line_start + col,
+    };
+
+    text[offset..].graphemes(true).count()
+}
+
+/// Reads the buffer file.
+/// Invalid UTF-8 sequences are replaced with U+FFFD, and then `true` is returned with the text.
+fn read_buffer(buffer_path: &Path) -> Option<(String, bool)> {
+    let bytes = std::fs::read(buffer_path).ok()?;
+    match String::from_utf8(bytes) {
+        Ok(text) => Some((text, false)),
+        Err(err) => {
+            log::warn!("vime: the buffer is not valid UTF-8: {}", err);
+            Some((String::from_utf8_lossy(err.as_bytes()).into_owned(), true))
+        }
+    }
+}
+
+/// Makes the event loop wake up by the deadline.
+fn wait_until(control_flow: &mut glium::glutin::event_loop::ControlFlow, deadline: Instant) {
+    use glium::glutin::event_loop::ControlFlow;
+    match *control_flow {
+        ControlFlow::Exit | ControlFlow::Poll => {}
+        ControlFlow::WaitUntil(t) if t <= deadline => {}
+        _ => *control_flow = ControlFlow::WaitUntil(deadline),
+    }
+}
+
+/// Merges the control flow requested by a terminal into the one of the event loop.
+fn merge_control_flow(
+    control_flow: &mut glium::glutin::event_loop::ControlFlow,
+    flow: glium::glutin::event_loop::ControlFlow,
+) {
+    use glium::glutin::event_loop::ControlFlow;
+    match flow {
+        ControlFlow::Poll => *control_flow = ControlFlow::Poll,
+        ControlFlow::WaitUntil(deadline) => wait_until(control_flow, deadline),
+        _ => {}
+    }
+}
+
+fn default_terminal_size() -> window::TerminalSize {
+    let rows = vime_config::CONFIG.default_rows;
+    let cols = vime_config::CONFIG.default_columns;
+    window::TerminalSize { rows, cols }
+}
+
+/// Clamps a terminal size requested by the editor into the configured limits.
+fn clamp_terminal_size(
+    size: window::TerminalSize,
+    config: &vime_config::Config,
+) -> window::TerminalSize {
+    let rows = size.rows.clamp(config.min_rows, config.max_rows.max(config.min_rows));
+    let cols = size.cols.clamp(config.min_columns, config.max_columns.max(config.min_columns));
+    window::TerminalSize { rows, cols }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clamp(rows: usize, cols: usize, config: &vime_config::Config) -> (usize, usize) {
+        let size = clamp_terminal_size(window::TerminalSize { rows, cols }, config);
+        (size.rows, size.cols)
+    }
+
+    #[test]
+    fn clamp_into_limits() {
+        let config = vime_config::Config {
+            min_rows: 3,
+            max_rows: 20,
+            min_columns: 10,
+            max_columns: 100,
+            ..Default::default()
+        };
+        assert_eq!(clamp(8, 40, &config), (8, 40));
+        assert_eq!(clamp(1, 200, &config), (3, 100));
+        assert_eq!(clamp(50, 2, &config), (20, 10));
+    }
+
+    #[test]
+    fn clamp_with_max_below_min() {
+        // the minimum wins
+        let config = vime_config::Config {
+            min_rows: 5,
+            max_rows: 2,
+            min_columns: 30,
+            max_columns: 0,
+            ..Default::default()
+        };
+        assert_eq!(clamp(1, 1, &config), (5, 30));
+        assert_eq!(clamp(9, 90, &config), (5, 30));
+    }
+
+    /// Writes the cursor position to a file, and reads it back for the text.
+    fn caret_back(name: &str, cursor: &str, text: &str) -> usize {
+        let path = std::env::temp_dir().join(format!("vime_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, cursor).unwrap();
+        let caret_back = read_caret_back(&path, text);
+        let _ = std::fs::remove_file(&path);
+        caret_back
+    }
+
+    #[test]
+    fn caret_back_after_cursor() {
+        // the caret goes after the character under the cursor
+        assert_eq!(caret_back("middle", "2 3", "hello\nworld"), 2);
+        assert_eq!(caret_back("end", "2 5", "hello\nworld"), 0);
+        assert_eq!(caret_back("first", "1 1", "hello\nworld"), 10);
+        // an empty line
+        assert_eq!(caret_back("empty_line", "2 1", "a\n\nb"), 2);
+    }
+
+    #[test]
+    fn caret_back_by_grapheme_clusters() {
+        // e + COMBINING ACUTE ACCENT, and the column is in bytes
+        assert_eq!(caret_back("combining", "1 1", "e\u{301}xy"), 2);
+        assert_eq!(caret_back("multibyte", "1 4", "あいう"), 1);
+    }
+
+    #[test]
+    fn caret_back_without_cursor() {
+        assert_eq!(caret_back("invalid", "abc", "hello"), 0);
+        let missing = std::env::temp_dir().join("vime_test_missing_cursor");
+        assert_eq!(read_caret_back(&missing, "hello"), 0);
+    }
+}
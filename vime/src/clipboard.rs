@@ -0,0 +1,168 @@
+use xcb::x::{Atom, Window};
+use xcb::Xid as _;
+
+use crate::create_dummy_window;
+
+/// Serves text through the CLIPBOARD and PRIMARY selections.
+///
+/// NOTE: The INCR protocol is not supported, so the text has to fit in a single request.
+pub struct Clipboard {
+    window: Window,
+    atoms: Atoms,
+
+    text: Option<String>,
+    // the selections currently owned by the window
+    owned: Vec<Atom>,
+}
+
+struct Atoms {
+    clipboard: Atom,
+    targets: Atom,
+    utf8_string: Atom,
+}
+
+impl Clipboard {
+    pub fn new(conn: &xcb::Connection, screen: &xcb::x::Screen) -> Self {
+        let window = create_dummy_window(conn, screen);
+
+        let intern_atom = |name: &[u8]| {
+            let cookie = conn.send_request(&xcb::x::InternAtom {
+                only_if_exists: false,
+                name,
+            });
+            conn.wait_for_reply(cookie).unwrap().atom()
+        };
+
+        let atoms = Atoms {
+            clipboard: intern_atom(b"CLIPBOARD"),
+            targets: intern_atom(b"TARGETS"),
+            utf8_string: intern_atom(b"UTF8_STRING"),
+        };
+
+        Self {
+            window,
+            atoms,
+            text: None,
+            owned: Vec::new(),
+        }
+    }
+
+    /// Takes the ownership of the selections to serve the text.
+    pub fn set_text(&mut self, conn: &xcb::Connection, text: String) {
+        self.owned.clear();
+
+        for selection in [self.atoms.clipboard, xcb::x::ATOM_PRIMARY] {
+            conn.send_request(&xcb::x::SetSelectionOwner {
+                owner: self.window,
+                selection,
+                time: xcb::x::CURRENT_TIME,
+            });
+
+            let cookie = conn.send_request(&xcb::x::GetSelectionOwner { selection });
+            match conn.wait_for_reply(cookie) {
+                Ok(reply) if reply.owner() == self.window => self.owned.push(selection),
+                _ => log::warn!("failed to own a selection"),
+            }
+        }
+
+        self.text = if self.owned.is_empty() {
+            None
+        } else {
+            Some(text)
+        };
+    }
+
+    /// Handles the selection events for the clipboard window.
+    /// Returns true if the event has been consumed.
+    pub fn process_event(&mut self, conn: &xcb::Connection, event: &xcb::Event) -> bool {
+        match event {
+            xcb::Event::X(xcb::x::Event::SelectionRequest(req)) if req.owner() == self.window => {
+                let property = self.convert(conn, req);
+
+                let notify = xcb::x::SelectionNotifyEvent::new(
+                    req.time(),
+                    req.requestor(),
+                    req.selection(),
+                    req.target(),
+                    property,
+                );
+                conn.send_request(&xcb::x::SendEvent {
+                    propagate: false,
+                    destination: xcb::x::SendEventDest::Window(req.requestor()),
+                    event_mask: xcb::x::EventMask::empty(),
+                    event: &notify,
+                });
+                conn.flush().unwrap();
+                true
+            }
+
+            xcb::Event::X(xcb::x::Event::SelectionClear(ev)) if ev.owner() == self.window => {
+                log::debug!("clipboard: lost a selection");
+                self.owned.retain(|&s| s != ev.selection());
+                if self.owned.is_empty() {
+                    self.text = None;
+                }
+                true
+            }
+
+            _ => false,
+        }
+    }
+
+    /// Stores the text to the property of the requestor in the requested type,
+    /// and returns the property, or `ATOM_NONE` if the request is refused.
+    fn convert(&self, conn: &xcb::Connection, req: &xcb::x::SelectionRequestEvent) -> Atom {
+        let Some(text) = &self.text else { return Atom::none() };
+        if !self.owned.contains(&req.selection()) {
+            return Atom::none();
+        }
+
+        // NOTE: obsolete clients may specify None as the property
+        let property = if req.property().is_none() {
+            req.target()
+        } else {
+            req.property()
+        };
+
+        let target = req.target();
+        if target == self.atoms.targets {
+            let targets = [
+                self.atoms.targets,
+                self.atoms.utf8_string,
+                xcb::x::ATOM_STRING,
+            ];
+            conn.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Replace,
+                window: req.requestor(),
+                property,
+                r#type: xcb::x::ATOM_ATOM,
+                data: &targets,
+            });
+        } else if target == self.atoms.utf8_string {
+            conn.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Replace,
+                window: req.requestor(),
+                property,
+                r#type: self.atoms.utf8_string,
+                data: text.as_bytes(),
+            });
+        } else if target == xcb::x::ATOM_STRING {
+            // STRING is encoded in Latin-1
+            let latin1: Vec<u8> = text
+                .chars()
+                .map(|ch| u8::try_from(ch).unwrap_or(b'?'))
+                .collect();
+            conn.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Replace,
+                window: req.requestor(),
+                property,
+                r#type: xcb::x::ATOM_STRING,
+                data: &latin1,
+            });
+        } else {
+            return Atom::none();
+        }
+
+        property
+    }
+}
@@ -0,0 +1,104 @@
+use xcb_imdkit::KeyEvent;
+use xkbcommon::xkb;
+
+use crate::key_input;
+
+/// Returns a compose state with the table which Xlib would use:
+/// `$XCOMPOSEFILE`, `~/.XCompose`, or the Compose file of the locale.
+pub fn new_state(context: &xkb::Context) -> Option<xkb::compose::State> {
+    // Compose sequences of the locale (e.g. <Multi_key> <'> <e> -> é)
+    let locale = std::env::var_os("LC_ALL")
+        .or_else(|| std::env::var_os("LC_CTYPE"))
+        .or_else(|| std::env::var_os("LANG"))
+        .unwrap_or_else(|| "C".into());
+
+    let table =
+        xkb::compose::Table::new_from_locale(context, &locale, xkb::compose::COMPILE_NO_FLAGS);
+    match table {
+        Ok(table) => {
+            let state = xkb::compose::State::new(&table, xkb::compose::STATE_NO_FLAGS);
+            Some(state)
+        }
+        Err(_) => {
+            log::warn!("compose: no compose table for {:?}", locale);
+            None
+        }
+    }
+}
+
+/// What to do with a key event.
+pub enum Composed {
+    /// Forwards the key event to the client as is.
+    Forward,
+    /// Drops the key event, which is a part of a compose sequence.
+    Consumed,
+    /// Commits the text instead of the key event.
+    Text(String),
+}
+
+/// Composes characters (e.g. <dead_acute> <e> -> é) from the key events
+/// passed through without an upstream IM, as Xlib does.
+pub struct Composer {
+    state: xkb::State,
+    compose: xkb::compose::State,
+    // keys whose presses have been consumed, so that their releases are as well
+    consumed: Vec<u8>,
+}
+
+impl Composer {
+    pub fn new(conn: &xcb::Connection) -> Option<Self> {
+        let (context, state) = key_input::keyboard_state(conn)?;
+        let compose = new_state(&context)?;
+        Some(Self {
+            state,
+            compose,
+            consumed: Vec::new(),
+        })
+    }
+
+    /// Forgets the sequence in progress (e.g. when the focus moves).
+    pub fn reset(&mut self) {
+        self.compose.reset();
+        self.consumed.clear();
+    }
+
+    pub fn feed(&mut self, key_event: &KeyEvent) -> Composed {
+        if !key_event.is_press {
+            let i = self.consumed.iter().position(|&k| k == key_event.detail);
+            return match i {
+                Some(i) => {
+                    self.consumed.remove(i);
+                    Composed::Consumed
+                }
+                None => Composed::Forward,
+            };
+        }
+
+        let keysym = key_input::update_state(&mut self.state, key_event);
+
+        // NOTE: Modifiers are ignored in the middle of a sequence
+        if let xkb::compose::FeedResult::Ignored = self.compose.feed(keysym) {
+            return Composed::Forward;
+        }
+
+        let composed = match self.compose.status() {
+            xkb::compose::Status::Nothing => return Composed::Forward,
+            xkb::compose::Status::Composing => Composed::Consumed,
+            xkb::compose::Status::Cancelled => {
+                // The key which breaks the sequence is dropped as Xlib does
+                self.compose.reset();
+                Composed::Consumed
+            }
+            xkb::compose::Status::Composed => {
+                let text = self.compose.utf8();
+                self.compose.reset();
+                match text {
+                    Some(text) if !text.is_empty() => Composed::Text(text),
+                    _ => Composed::Consumed,
+                }
+            }
+        };
+        self.consumed.push(key_event.detail);
+        composed
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+/// Returns the path to the draft file of the clients of the class name in WM_CLASS,
+/// so that a draft is reopened only in the application where it was written.
+pub fn path(class: &str) -> Option<PathBuf> {
+    // NOTE: The class name is untrusted, and may contain '/' or ".."
+    let name: String = class
+        .chars()
+        .map(|ch| match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => ch,
+            _ => '_',
+        })
+        .collect();
+
+    let mut path = vime_config::data_dir()?;
+    path.push("drafts");
+    // e.g. the scratchpad
+    path.push(if name.is_empty() { "_" } else { &name });
+    path.set_extension("txt");
+    Some(path)
+}
+
+/// Saves the text as a draft for the class, and returns the path to the draft file.
+pub fn save(class: &str, text: &str) -> std::io::Result<PathBuf> {
+    use std::io::{Error, ErrorKind};
+
+    let path = path(class).ok_or_else(|| Error::new(ErrorKind::NotFound, "no data directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_private(&path, text)?;
+    Ok(path)
+}
+
+/// Takes the draft for the class out of the draft file if any.
+pub fn take(class: &str) -> Option<String> {
+    let path = path(class)?;
+    let text = std::fs::read_to_string(&path).ok()?;
+    let _ = std::fs::remove_file(&path);
+    Some(text)
+}
+
+/// Replaces the file with the text, which only the user can read.
+/// The text is written to a new file and renamed, so that a symlink is never followed
+/// and the permissions of an existing file are not kept.
+pub fn write_private(path: &Path, text: &str) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let _ = std::fs::remove_file(&tmp_path);
+    write_new_private(&tmp_path, text)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Writes the text to a new file which only the user can read.
+/// Fails if the file (or a symlink) already exists.
+pub fn write_new_private(path: &Path, text: &str) -> std::io::Result<()> {
+    use std::io::Write as _;
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(text.as_bytes())
+}
+
+/// Creates the runtime directory (see `vime_config::runtime_dir`) which only the user can access,
+/// and returns the path to it.
+pub fn create_runtime_dir() -> std::io::Result<PathBuf> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::{DirBuilderExt as _, PermissionsExt as _};
+
+    let dir = vime_config::runtime_dir()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no runtime directory"))?;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+
+    // NOTE: An existing directory may be a symlink, or have been created with another mode
+    if !std::fs::symlink_metadata(&dir)?.is_dir() {
+        let msg = format!("not a directory: {}", dir.display());
+        return Err(Error::other(msg));
+    }
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
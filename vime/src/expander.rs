@@ -0,0 +1,305 @@
+use std::io::Read as _;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use unicode_segmentation::UnicodeSegmentation as _;
+use vime_config::Expansion;
+use xcb_imdkit::{Ic, KeyEvent};
+use xkbcommon::xkb;
+use xkbcommon::xkb::keysyms as ks;
+
+use crate::key_input;
+use crate::keyboard;
+
+// bytes of the typed text to keep, which is enough for any abbreviation
+const MAX_TYPED: usize = 256;
+
+/// How long the command of an expansion may run before it is killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Watches the text typed in the passthrough mode, and finds the abbreviations
+/// to expand (e.g. `;sig` -> a signature).
+pub struct Expander {
+    state: xkb::State,
+    modifier_keycodes: Vec<u8>,
+    typed: Option<Typed>,
+    // the IC whose abbreviation is waiting for the command, until anything else is typed
+    pending: Option<Ic>,
+}
+
+/// The text typed in an IC since the caret may have moved.
+struct Typed {
+    ic: Ic,
+    // the class name in WM_CLASS of the client
+    class: String,
+    text: String,
+}
+
+/// An abbreviation which has just been typed.
+pub struct Abbreviation {
+    /// the number of grapheme clusters to delete
+    pub erase: usize,
+    pub expansion: Expansion,
+}
+
+impl Expander {
+    /// Returns an expander if any expansion is configured.
+    pub fn new(conn: &xcb::Connection) -> Option<Self> {
+        let config = &vime_config::CONFIG;
+        let mut profiles = config.profiles.values();
+        if config.expansions.is_empty() && profiles.all(|p| p.expansions.is_empty()) {
+            return None;
+        }
+
+        let (_, state) = key_input::keyboard_state(conn)?;
+        Some(Self {
+            state,
+            modifier_keycodes: keyboard::modifier_keycodes(conn),
+            typed: None,
+            pending: None,
+        })
+    }
+
+    /// Forgets the text typed so far (e.g. when the focus moves).
+    pub fn reset(&mut self) {
+        self.typed = None;
+        self.pending = None;
+    }
+
+    /// Returns true if the abbreviation typed in the IC can still be replaced
+    /// with the output of the command, which has just finished.
+    pub fn take_pending(&mut self, ic: &Ic) -> bool {
+        self.pending.take().is_some_and(|pending| pending == *ic)
+    }
+
+    /// Follows the key forwarded to the client, and returns the abbreviation which it completes.
+    /// `class` is called to get the class name of the client when a new IC is seen.
+    pub fn feed_key(
+        &mut self,
+        ic: &Ic,
+        key_event: &KeyEvent,
+        class: impl FnOnce() -> String,
+    ) -> Option<Abbreviation> {
+        if !key_event.is_press || self.modifier_keycodes.contains(&key_event.detail) {
+            return None;
+        }
+        self.pending = None;
+
+        let keysym = key_input::update_state(&mut self.state, key_event);
+        let text = self.state.key_get_utf8(key_event.detail as xkb::Keycode);
+
+        // NOTE: Ctrl+A, arrows, Return, etc. may move the caret or edit the text
+        let ctrl_alt = key_event.state.bits() & (0x4 | 0x8) != 0;
+        if keysym == ks::KEY_BackSpace && !ctrl_alt {
+            if let Some(typed) = &mut self.typed {
+                typed.text.pop();
+            }
+            return None;
+        }
+        if ctrl_alt || text.is_empty() || text.chars().any(char::is_control) {
+            self.reset();
+            return None;
+        }
+
+        self.feed_text(ic, &text, class)
+    }
+
+    /// Follows the text committed to the client, and returns the abbreviation which it completes.
+    pub fn feed_text(
+        &mut self,
+        ic: &Ic,
+        text: &str,
+        class: impl FnOnce() -> String,
+    ) -> Option<Abbreviation> {
+        self.pending = None;
+        if !self.typed.as_ref().is_some_and(|typed| typed.ic == *ic) {
+            self.typed = Some(Typed {
+                ic: ic.clone(),
+                class: class(),
+                text: String::new(),
+            });
+        }
+        let typed = self.typed.as_mut().unwrap();
+        push_typed(&mut typed.text, text);
+
+        let expansions = vime_config::CONFIG.expansions(&typed.class);
+        let abbreviation = find_abbreviation(&typed.text, expansions)?;
+
+        // The abbreviation is replaced, so it can't be a part of the next one
+        self.reset();
+        if !abbreviation.expansion.command.is_empty() {
+            self.pending = Some(ic.clone());
+        }
+        Some(abbreviation)
+    }
+}
+
+/// Appends the text, and drops the oldest characters over `MAX_TYPED` bytes.
+fn push_typed(typed: &mut String, text: &str) {
+    typed.push_str(text);
+    if typed.len() > MAX_TYPED {
+        let start = typed.len() - MAX_TYPED;
+        let start = (start..typed.len()).find(|&i| typed.is_char_boundary(i));
+        typed.drain(..start.unwrap_or(0));
+    }
+}
+
+/// Returns the first expansion whose trigger the typed text ends with.
+fn find_abbreviation<'a>(
+    typed: &str,
+    mut expansions: impl Iterator<Item = &'a Expansion>,
+) -> Option<Abbreviation> {
+    let expansion = expansions.find(|e| !e.trigger.is_empty() && typed.ends_with(&e.trigger))?;
+    Some(Abbreviation {
+        erase: expansion.trigger.graphemes(true).count(),
+        expansion: expansion.clone(),
+    })
+}
+
+/// Runs the command of the expansion, and returns its output.
+/// The command is killed if it doesn't finish within the timeout.
+///
+/// NOTE: This blocks, so the IM server calls it on a worker thread.
+pub fn run_command(command: &[String]) -> Option<String> {
+    let (program, args) = command.split_first()?;
+
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            log::warn!("expander: failed to run {:?}: {}", program, err);
+            return None;
+        }
+    };
+
+    // Read the output on another thread, so that the command never blocks on a full pipe.
+    // NOTE: The reader is abandoned if a process started by the command keeps the pipe open.
+    let mut stdout = child.stdout.take().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = tx.send(stdout.read_to_end(&mut output).map(|_| output));
+    });
+
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok(None) => {
+                log::warn!("expander: {:?} timed out", program);
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+            Err(err) => {
+                log::warn!("expander: failed to wait for {:?}: {}", program, err);
+                return None;
+            }
+        }
+    };
+    if !status.success() {
+        log::warn!("expander: {:?} exited with {}", program, status);
+        return None;
+    }
+
+    let output = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(output) => output.ok()?,
+        Err(_) => {
+            log::warn!(
+                "expander: the output of {:?} is not closed in time",
+                program
+            );
+            return None;
+        }
+    };
+    // Trailing newlines are dropped as `$(...)` does
+    let text = String::from_utf8_lossy(&output);
+    Some(text.trim_end_matches('\n').to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expansion(trigger: &str, text: &str) -> Expansion {
+        Expansion {
+            trigger: trigger.to_owned(),
+            text: text.to_owned(),
+            command: Vec::new(),
+        }
+    }
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
+    #[test]
+    fn typed_text_is_limited() {
+        let mut typed = String::new();
+        push_typed(&mut typed, &"a".repeat(MAX_TYPED));
+        push_typed(&mut typed, "bc");
+        assert_eq!(typed.len(), MAX_TYPED);
+        assert!(typed.ends_with("abc"));
+
+        // not cut in the middle of a character
+        let mut typed = String::from("あ");
+        push_typed(&mut typed, &"a".repeat(MAX_TYPED - 2));
+        assert_eq!(typed, "a".repeat(MAX_TYPED - 2));
+    }
+
+    #[test]
+    fn abbreviation_at_the_end() {
+        let expansions = [expansion("", "empty"), expansion(";sig", "Regards")];
+        let find = |typed| find_abbreviation(typed, expansions.iter());
+
+        let abbreviation = find("hello ;sig").expect("not found");
+        assert_eq!(abbreviation.expansion.text, "Regards");
+        assert_eq!(abbreviation.erase, 4);
+
+        assert!(find(";sig ").is_none());
+        assert!(find("").is_none());
+    }
+
+    #[test]
+    fn first_abbreviation_wins() {
+        // e.g. an expansion of a profile comes before the global one
+        let expansions = [expansion("sig", "profile"), expansion("sig", "global")];
+        let abbreviation = find_abbreviation(";sig", expansions.iter()).expect("not found");
+        assert_eq!(abbreviation.expansion.text, "profile");
+    }
+
+    #[test]
+    fn erase_by_grapheme_clusters() {
+        // e + COMBINING ACUTE ACCENT, and a flag of two regional indicators
+        let expansions = [expansion(";e\u{301}\u{1F1EF}\u{1F1F5}", "")];
+        let abbreviation = find_abbreviation("x;e\u{301}\u{1F1EF}\u{1F1F5}", expansions.iter());
+        assert_eq!(abbreviation.expect("not found").erase, 3);
+    }
+
+    #[test]
+    fn command_output() {
+        let output = run_command(&command(&["sh", "-c", "printf 'a\\nb\\n\\n'"]));
+        assert_eq!(output.as_deref(), Some("a\nb"));
+
+        assert_eq!(run_command(&command(&["sh", "-c", "echo a; exit 1"])), None);
+        assert_eq!(run_command(&command(&["vime-no-such-command"])), None);
+        assert_eq!(run_command(&[]), None);
+    }
+
+    #[test]
+    fn command_with_background_process() {
+        // the background process keeps the output open after the command exits
+        let start = Instant::now();
+        let output = run_command(&command(&["sh", "-c", "echo a; sleep 10 &"]));
+        assert_eq!(output, None);
+        assert!(start.elapsed() < COMMAND_TIMEOUT + Duration::from_secs(1));
+    }
+}
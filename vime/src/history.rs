@@ -0,0 +1,284 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A text committed through vime.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// seconds since the UNIX epoch
+    pub time: u64,
+    /// the class name in WM_CLASS of the target (empty for a scratchpad)
+    pub class: String,
+    pub text: String,
+}
+
+/// Returns the path to the history file, which has an entry in JSON per line.
+pub fn path() -> Option<PathBuf> {
+    let mut path = vime_config::data_dir()?;
+    path.push("history.jsonl");
+    Some(path)
+}
+
+/// Returns the path to the file of the entry chosen by `vime history pick`.
+fn picked_path() -> Option<PathBuf> {
+    let mut path = vime_config::data_dir()?;
+    path.push("history_picked.txt");
+    Some(path)
+}
+
+fn now() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH);
+    elapsed.map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn is_excluded(class: &str) -> bool {
+    let exclude = &vime_config::CONFIG.history_exclude;
+    exclude.iter().any(|c| c.eq_ignore_ascii_case(class))
+}
+
+/// Adds the text to the history if enabled, and drops the entries over the limits.
+pub fn record(class: &str, text: &str) {
+    if !vime_config::CONFIG.history || text.is_empty() || is_excluded(class) {
+        return;
+    }
+
+    let entry = Entry {
+        time: now(),
+        class: class.to_owned(),
+        text: text.to_owned(),
+    };
+    if let Err(err) = append(entry) {
+        log::error!("failed to write the history: {}", err);
+    }
+}
+
+fn append(entry: Entry) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    let path = path().ok_or_else(|| Error::new(ErrorKind::NotFound, "no data directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut entries = load();
+    entries.push(entry);
+    prune(&mut entries, now(), &vime_config::CONFIG);
+
+    let mut contents = String::new();
+    for entry in entries.iter() {
+        contents += &serde_json::to_string(entry).map_err(Error::from)?;
+        contents.push('\n');
+    }
+    crate::draft::write_private(&path, &contents)
+}
+
+/// Drops the entries older than `history_max_days`, and the oldest ones over the maximum number.
+fn prune(entries: &mut Vec<Entry>, now: u64, config: &vime_config::Config) {
+    if config.history_max_days != 0 {
+        let max_age = config.history_max_days * 24 * 60 * 60;
+        entries.retain(|entry| now.saturating_sub(entry.time) <= max_age);
+    }
+    if entries.len() > config.history_max_entries {
+        entries.drain(..entries.len() - config.history_max_entries);
+    }
+}
+
+/// Reads all the entries, from the oldest to the newest.
+pub fn load() -> Vec<Entry> {
+    let Some(path) = path() else { return Vec::new() };
+    let Ok(contents) = std::fs::read_to_string(path) else { return Vec::new() };
+
+    let mut entries = Vec::new();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => log::warn!("history: invalid entry: {}", err),
+        }
+    }
+    entries
+}
+
+/// Returns the text to open with the recall key: the entry picked by `vime history pick`,
+/// or the newest one committed to the same application.
+pub fn recall(class: &str) -> Option<String> {
+    if let Some(path) = picked_path() {
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            let _ = std::fs::remove_file(&path);
+            return Some(text);
+        }
+    }
+
+    let entries = load();
+    let entry = entries.iter().rev().find(|entry| entry.class == class);
+    entry.map(|entry| entry.text.clone())
+}
+
+const USAGE: &str = "\
+Usage:
+    vime history [--class <CLASS>] [QUERY]   list the entries containing QUERY, newest first
+    vime history show <N>                    print the text of the N-th entry in the list
+    vime history pick <N>                    open the N-th entry with the recall key next time
+    vime history clear                       delete the history";
+
+/// Returns the entries with the class and containing the query, numbered from 0 in `entries`.
+fn filter<'a>(
+    entries: &'a [Entry],
+    class: Option<&'a str>,
+    query: Option<&'a str>,
+) -> impl Iterator<Item = (usize, &'a Entry)> {
+    entries.iter().enumerate().filter(move |(_, entry)| {
+        let other_class = class.is_some_and(|class| !entry.class.eq_ignore_ascii_case(class));
+        let unmatched = query.is_some_and(|query| !entry.text.contains(query));
+        !other_class && !unmatched
+    })
+}
+
+/// Runs `vime history` with the arguments after it, and returns the exit code.
+pub fn command(args: &[String]) -> i32 {
+    let mut args = args.iter().map(|arg| arg.as_str());
+
+    let mut entries = load();
+    entries.reverse();
+
+    match args.next() {
+        Some("show") | Some("pick") if args.len() != 1 => {
+            eprintln!("{}", USAGE);
+            2
+        }
+        Some(cmd @ ("show" | "pick")) => {
+            let n = args.next().unwrap();
+            let index = n.parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+            let Some(entry) = index.and_then(|i| entries.get(i)) else {
+                eprintln!("vime: no entry {}", n);
+                return 1;
+            };
+
+            if cmd == "show" {
+                println!("{}", entry.text);
+                return 0;
+            }
+            let written = picked_path()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+                .and_then(|path| crate::draft::write_private(&path, &entry.text));
+            match written {
+                Ok(()) => 0,
+                Err(err) => {
+                    eprintln!("vime: failed to pick the entry: {}", err);
+                    1
+                }
+            }
+        }
+        Some("clear") => {
+            if let Some(path) = path() {
+                let _ = std::fs::remove_file(path);
+            }
+            0
+        }
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            0
+        }
+        first => {
+            let mut class = None;
+            let mut query = None;
+            let mut arg = first;
+            while let Some(a) = arg {
+                match a {
+                    "--class" => class = args.next(),
+                    _ => query = Some(a),
+                }
+                arg = args.next();
+            }
+
+            for (i, entry) in filter(&entries, class, query) {
+                let time = UNIX_EPOCH + Duration::from_secs(entry.time);
+                let first_line = entry.text.lines().next().unwrap_or_default();
+                let summary: String = first_line.chars().take(60).collect();
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    i + 1,
+                    humantime::format_rfc3339_seconds(time),
+                    entry.class,
+                    summary
+                );
+            }
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn entry(time: u64, class: &str, text: &str) -> Entry {
+        Entry {
+            time,
+            class: class.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    fn texts(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.text.as_str()).collect()
+    }
+
+    #[test]
+    fn prune_old_entries() {
+        let config = vime_config::Config {
+            history_max_days: 2,
+            history_max_entries: 10,
+            ..Default::default()
+        };
+        let now = 10 * DAY;
+        let mut entries = vec![
+            entry(now - 3 * DAY, "a", "old"),
+            entry(now - 2 * DAY, "a", "two days"),
+            entry(now, "a", "new"),
+        ];
+        prune(&mut entries, now, &config);
+        assert_eq!(texts(&entries), ["two days", "new"]);
+    }
+
+    #[test]
+    fn prune_over_max_entries() {
+        let config = vime_config::Config {
+            history_max_days: 0,
+            history_max_entries: 2,
+            ..Default::default()
+        };
+        let mut entries = vec![
+            entry(0, "a", "1"),
+            entry(DAY, "a", "2"),
+            entry(2 * DAY, "a", "3"),
+        ];
+        prune(&mut entries, 1000 * DAY, &config);
+        assert_eq!(texts(&entries), ["2", "3"]);
+    }
+
+    #[test]
+    fn filter_by_class_and_query() {
+        let entries = [
+            entry(0, "Firefox", "hello world"),
+            entry(0, "Slack", "hello"),
+            entry(0, "firefox", "bye"),
+        ];
+        let numbers = |class, query| -> Vec<usize> {
+            filter(&entries, class, query).map(|(i, _)| i).collect()
+        };
+        assert_eq!(numbers(None, None), [0, 1, 2]);
+        assert_eq!(numbers(Some("FIREFOX"), None), [0, 2]);
+        assert_eq!(numbers(None, Some("hello")), [0, 1]);
+        assert_eq!(numbers(Some("firefox"), Some("hello")), [0]);
+    }
+
+    #[test]
+    fn command_with_invalid_arguments() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|&a| a.to_owned()).collect() };
+        assert_eq!(command(&args(&["show"])), 2);
+        assert_eq!(command(&args(&["pick", "1", "2"])), 2);
+        assert_eq!(command(&args(&["show", "0"])), 1);
+        assert_eq!(command(&args(&["show", "x"])), 1);
+    }
+}
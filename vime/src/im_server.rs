@@ -1,12 +1,21 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::rc::Rc;
-use std::sync::{atomic, Arc};
+use std::sync::Arc;
 
+use unicode_segmentation::UnicodeSegmentation as _;
+use vime_config::Placement;
 use xcb::x::Window;
 use xcb::Xid as _;
-use xcb_imdkit::{Ic, ImeServer, ImeServerCallbacks};
+use xcb_imdkit::{Ic, ImeServer, ImeServerCallbacks, KeyEvent, PreeditDraw};
 
-use crate::{create_dummy_window, is_vime_message, notify, Channel, Message};
+use crate::clipboard::Clipboard;
+use crate::expander::{self, Abbreviation, Expander};
+use crate::history;
+use crate::key_input::KeyInput;
+use crate::keyboard::{self, XK_BACKSPACE, XK_LEFT, XK_RETURN, XK_RIGHT};
+use crate::placement::{self, Rect, Surroundings};
+use crate::{create_dummy_window, is_vime_message, notify, Channel, Client, Message, SessionMode};
 
 const IM_NAME: &str = "vime";
 
@@ -14,6 +23,7 @@ const IM_NAME: &str = "vime";
 struct Context {
     conn: Arc<xcb::Connection>,
     default_screen: i32,
+    root: Window,
 
     chan_vime: Rc<Channel>,
     chan_passthru: Rc<Channel>,
@@ -24,6 +34,26 @@ struct Context {
 
     current_ic: Rc<RefCell<Option<Ic>>>,
     vime_state: VimeState,
+    last_commits: LastCommits,
+
+    // translates the keys for sessions without going through the vime window
+    key_input: Rc<RefCell<Option<KeyInput>>>,
+    // expands the abbreviations typed in the passthrough mode
+    expander: Rc<RefCell<Option<Expander>>>,
+    // the results of the expansion commands, which run on worker threads
+    chan_expander: Rc<Channel>,
+
+    // absolute position of the spot of the current IC
+    spot: Rc<Cell<(i16, i16)>>,
+    // the client window which the spot belongs to, and the position of the spot in it
+    spot_window: Rc<Cell<Window>>,
+    spot_offset: Rc<Cell<(i16, i16)>>,
+    // the spot window and its ancestors, whose ConfigureNotify moves the session window,
+    // with their event masks to restore
+    tracked_windows: Rc<RefCell<Vec<(Window, xcb::x::EventMask)>>>,
+
+    // font size of each vime window scaled by the DPI of the monitor
+    font_sizes: Rc<RefCell<HashMap<Window, u32>>>,
 }
 
 impl Context {
@@ -39,6 +69,7 @@ impl Context {
             .nth(default_screen as usize)
             .unwrap();
 
+        let root = screen.root();
         let win_server = create_dummy_window(&conn, screen);
 
         let Ok(Message::Window(win_vime)) = chan_vime.rx.recv() else { panic!("bug") };
@@ -50,11 +81,20 @@ impl Context {
         chan_passthru.tx.send(Message::Window(win_server)).unwrap();
 
         let current_ic = Rc::new(RefCell::new(None));
-        let vime_state = VimeState::new(false);
+        let vime_state = VimeState::new();
+        let expander = Expander::new(&conn);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let chan_expander = Rc::new(Channel { tx, rx });
+
+        let key_input = KeyInput::new(&conn);
+        if key_input.is_none() {
+            log::warn!("no XKB keymap to translate the keys for the editor");
+        }
 
         Self {
             conn,
             default_screen,
+            root,
             chan_vime,
             chan_passthru,
             win_server,
@@ -62,28 +102,154 @@ impl Context {
             win_passthru,
             current_ic,
             vime_state,
+            last_commits: LastCommits::new(),
+            key_input: Rc::new(RefCell::new(key_input)),
+            expander: Rc::new(RefCell::new(expander)),
+            chan_expander,
+            spot: Rc::new(Cell::new((0, 0))),
+            spot_window: Rc::new(Cell::new(Window::none())),
+            spot_offset: Rc::new(Cell::new((0, 0))),
+            tracked_windows: Rc::new(RefCell::new(Vec::new())),
+            font_sizes: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 }
 
+/// A vime session running for an IC.
+struct Session {
+    ic: Ic,
+    mode: SessionMode,
+    client: Client,
+    placement: Placement,
+
+    // the window of the session, which is told by the vime backend
+    window: Option<Window>,
+    // a pinned session stays open across focus changes
+    pinned: bool,
+
+    // the text opened instead of the draft
+    text: Option<String>,
+    // the commit which the text of the session replaces
+    amend: Option<LastCommit>,
+}
+
 #[derive(Clone)]
 struct VimeState {
-    active: Rc<atomic::AtomicBool>,
+    sessions: Rc<RefCell<Vec<Session>>>,
 }
 impl VimeState {
-    fn new(active: bool) -> Self {
+    fn new() -> Self {
+        Self {
+            sessions: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+    fn with_session<T>(&self, ic: &Ic, f: impl FnOnce(&mut Session) -> T) -> Option<T> {
+        let mut sessions = self.sessions.borrow_mut();
+        sessions.iter_mut().find(|s| s.ic == *ic).map(f)
+    }
+    /// Returns whether the key events from the IC should go to a vime session.
+    fn is_active_for(&self, ic: &Ic) -> bool {
+        self.with_session(ic, |_| ()).is_some()
+    }
+    /// Returns the message to show the session of the IC if any.
+    fn start_message(&self, ic: &Ic) -> Option<Message> {
+        self.with_session(ic, |s| {
+            let text = s.text.clone();
+            Message::StartSession(s.ic.clone(), s.mode, s.client.clone(), text)
+        })
+    }
+    fn is_pinned(&self, ic: &Ic) -> bool {
+        self.with_session(ic, |s| s.pinned).unwrap_or(false)
+    }
+    fn toggle_pinned(&self, ic: &Ic) -> bool {
+        self.with_session(ic, |s| {
+            s.pinned = !s.pinned;
+            s.pinned
+        })
+        .unwrap_or(false)
+    }
+    fn start(
+        &self,
+        ic: Ic,
+        mode: SessionMode,
+        client: Client,
+        text: Option<String>,
+        amend: Option<LastCommit>,
+    ) {
+        let config = &vime_config::CONFIG;
+        let placement = config
+            .profile(&client.class)
+            .and_then(|profile| profile.placement)
+            .unwrap_or(config.placement);
+
+        self.sessions.borrow_mut().push(Session {
+            ic,
+            mode,
+            client,
+            placement,
+            window: None,
+            pinned: false,
+            text,
+            amend,
+        });
+    }
+    fn inactivate(&self, ic: &Ic) {
+        self.sessions.borrow_mut().retain(|s| s.ic != *ic);
+    }
+    fn window(&self, ic: &Ic) -> Option<Window> {
+        self.with_session(ic, |s| s.window).flatten()
+    }
+    fn client_class(&self, ic: &Ic) -> Option<String> {
+        self.with_session(ic, |s| s.client.class.clone())
+    }
+    fn client_window(&self, ic: &Ic) -> Option<Window> {
+        self.with_session(ic, |s| s.client.window).flatten()
+    }
+    fn mode(&self, ic: &Ic) -> Option<SessionMode> {
+        self.with_session(ic, |s| s.mode)
+    }
+    fn take_amend(&self, ic: &Ic) -> Option<LastCommit> {
+        self.with_session(ic, |s| s.amend.take()).flatten()
+    }
+    fn placement(&self, ic: &Ic) -> Option<Placement> {
+        self.with_session(ic, |s| s.placement)
+    }
+    fn set_window(&self, ic: &Ic, window: Window) {
+        self.with_session(ic, |s| s.window = Some(window));
+    }
+}
+
+/// The text committed last to an IC, which can be amended or undone
+/// until another key is typed in the IC.
+#[derive(Clone)]
+struct LastCommit {
+    ic: Ic,
+    text: String,
+    // how many times Left was sent after the text to restore the caret
+    caret_back: usize,
+}
+
+#[derive(Clone)]
+struct LastCommits {
+    commits: Rc<RefCell<Vec<LastCommit>>>,
+}
+impl LastCommits {
+    fn new() -> Self {
         Self {
-            active: Rc::new(atomic::AtomicBool::new(active)),
+            commits: Rc::new(RefCell::new(Vec::new())),
         }
     }
-    fn is_active(&self) -> bool {
-        self.active.load(atomic::Ordering::SeqCst)
+    fn set(&self, commit: LastCommit) {
+        self.forget(&commit.ic);
+        self.commits.borrow_mut().push(commit);
     }
-    fn inactivate(&self) {
-        self.active.store(false, atomic::Ordering::SeqCst)
+    fn take(&self, ic: &Ic) -> Option<LastCommit> {
+        let mut commits = self.commits.borrow_mut();
+        let i = commits.iter().position(|commit| commit.ic == *ic)?;
+        Some(commits.remove(i))
     }
-    fn toggle(&self) -> bool {
-        self.active.fetch_xor(true, atomic::Ordering::SeqCst)
+    fn forget(&self, ic: &Ic) {
+        self.commits.borrow_mut().retain(|commit| commit.ic != *ic);
     }
 }
 
@@ -96,82 +262,211 @@ pub fn main(chan_vime: Channel, chan_passthru: Channel) {
 
         focus_in: {
             let c = c.clone();
-            Box::new(move |_, ic| {
+            Box::new(move |server, ic| {
                 *c.current_ic.borrow_mut() = Some(ic.clone());
                 log::debug!("focus_in");
 
-                if c.vime_state.is_active() {
-                    c.chan_vime.tx.send(Message::StartPreedit(ic)).unwrap();
+                // NOTE: The caret may have moved while the focus was away
+                if let Some(expander) = c.expander.borrow_mut().as_mut() {
+                    expander.reset();
+                }
+
+                if let Some(msg) = c.vime_state.start_message(&ic) {
+                    c.chan_vime.tx.send(msg).unwrap();
+                    notify(&c.conn, c.win_server, c.win_vime);
                 } else {
-                    c.chan_passthru.tx.send(Message::StartPreedit(ic)).unwrap();
-                    notify(&c.conn, c.win_server, c.win_passthru);
+                    start_preedit(&c, server, ic);
                 }
             })
         },
         focus_out: {
             let c = c.clone();
             Box::new(move |_, ic| {
-                if *c.current_ic.borrow() == Some(ic) {
+                if *c.current_ic.borrow() == Some(ic.clone()) {
                     *c.current_ic.borrow_mut() = None;
                     log::debug!("focus_out");
+                    untrack_client_windows(&c);
 
-                    if c.vime_state.is_active() {
-                        c.chan_vime.tx.send(Message::FocusOut).unwrap();
+                    // NOTE: a pinned session stays visible
+                    if c.vime_state.is_active_for(&ic) && !c.vime_state.is_pinned(&ic) {
+                        c.chan_vime.tx.send(Message::FocusOut(ic)).unwrap();
+                        notify(&c.conn, c.win_server, c.win_vime);
                     }
                 }
             })
         },
 
+        destroy_ic: {
+            let c = c.clone();
+            Box::new(move |_, ic| {
+                log::debug!("destroy_ic");
+
+                // NOTE: A pending expansion must not be committed to the freed IC
+                if let Some(expander) = c.expander.borrow_mut().as_mut() {
+                    expander.reset();
+                }
+
+                // The text can't be amended or undone in the freed IC
+                c.last_commits.forget(&ic);
+
+                // The text of the session is saved as a draft by the vime backend
+                if c.vime_state.is_active_for(&ic) {
+                    c.vime_state.inactivate(&ic);
+                    let msg = Message::DropSession(ic.clone());
+                    c.chan_vime.tx.send(msg).unwrap();
+                    notify(&c.conn, c.win_server, c.win_vime);
+                }
+
+                if *c.current_ic.borrow() == Some(ic) {
+                    *c.current_ic.borrow_mut() = None;
+                    untrack_client_windows(&c);
+
+                    c.chan_passthru.tx.send(Message::CancelPreedit).unwrap();
+                    notify(&c.conn, c.win_server, c.win_passthru);
+                }
+            })
+        },
+
         forward: {
             let c = c.clone();
 
-            let trigger_key_state = vime_config::CONFIG.trigger_key_state;
-            let trigger_key_state = xcb::x::KeyButMask::from_bits(trigger_key_state).unwrap();
-            let trigger_key_keycode = vime_config::CONFIG.trigger_key_keycode;
+            let config = &vime_config::CONFIG;
 
-            Box::new(move |_, ic, mut key_event| {
-                if *c.current_ic.borrow() != Some(ic) {
+            // NOTE: No window is given if the vime backend failed to start,
+            // and the keys can't be written to the editor without the keymap
+            let is_vime_available = !c.win_vime.is_none() && c.key_input.borrow().is_some();
+
+            // NOTE: A trigger with more modifiers should come first
+            // because the modifiers of a key event are matched with `contains`.
+            let triggers: Vec<_> = [
+                (
+                    SessionMode::Line,
+                    config.line_mode_trigger_key_state,
+                    config.line_mode_trigger_key_keycode,
+                ),
+                (
+                    SessionMode::Normal,
+                    config.trigger_key_state,
+                    config.trigger_key_keycode,
+                ),
+            ]
+            .into_iter()
+            .filter(|&(_, _, keycode)| keycode != 0 && is_vime_available)
+            .map(|(mode, state, keycode)| {
+                let state = xcb::x::KeyButMask::from_bits(state).unwrap();
+                (mode, state, keycode)
+            })
+            .collect();
+
+            let pin_key_state = xcb::x::KeyButMask::from_bits(config.pin_key_state).unwrap();
+            let pin_key_keycode = config.pin_key_keycode;
+
+            let amend_key_state = xcb::x::KeyButMask::from_bits(config.amend_key_state).unwrap();
+            let amend_key_keycode = config.amend_key_keycode;
+            let undo_key_state = xcb::x::KeyButMask::from_bits(config.undo_key_state).unwrap();
+            let undo_key_keycode = config.undo_key_keycode;
+            let recall_key_state = xcb::x::KeyButMask::from_bits(config.recall_key_state).unwrap();
+            let recall_key_keycode = config.recall_key_keycode;
+            let upstream_im_key_state =
+                xcb::x::KeyButMask::from_bits(config.upstream_im_key_state).unwrap();
+            let upstream_im_key_keycode = config.upstream_im_key_keycode;
+
+            // NOTE: Pressing a modifier alone doesn't move the caret
+            let modifier_keycodes = keyboard::modifier_keycodes(&c.conn);
+
+            Box::new(move |server, ic, key_event| {
+                if *c.current_ic.borrow() != Some(ic.clone()) {
                     log::trace!("forward: mismatch ic");
                     return;
                 }
 
                 // FIXME: use key-symbol
-                if key_event.state.contains(trigger_key_state)
-                    && key_event.detail == trigger_key_keycode
-                {
-                    if key_event.is_press {
-                        if !c.vime_state.toggle() {
-                            c.chan_passthru.tx.send(Message::CancelPreedit).unwrap();
-                            notify(&c.conn, c.win_server, c.win_passthru);
+                let trigger = triggers.iter().find(|(_, state, keycode)| {
+                    key_event.state.contains(*state) && key_event.detail == *keycode
+                });
+
+                let is_key = |state: xcb::x::KeyButMask, keycode: u8| {
+                    keycode != 0 && key_event.state.contains(state) && key_event.detail == keycode
+                };
+                let is_pin_key = is_key(pin_key_state, pin_key_keycode);
+                let is_amend_key = is_vime_available && is_key(amend_key_state, amend_key_keycode);
+                let is_undo_key = is_key(undo_key_state, undo_key_keycode);
+                let is_recall_key =
+                    is_vime_available && is_key(recall_key_state, recall_key_keycode);
+                let is_upstream_im_key = is_key(upstream_im_key_state, upstream_im_key_keycode);
 
-                            let ic = c.current_ic.borrow().clone().unwrap();
-                            c.chan_vime.tx.send(Message::StartPreedit(ic)).unwrap();
+                if is_pin_key && c.vime_state.is_active_for(&ic) {
+                    if key_event.is_press {
+                        let pinned = c.vime_state.toggle_pinned(&ic);
+                        log::debug!("forward: pinned={}", pinned);
+                        c.chan_vime.tx.send(Message::Pin(ic, pinned)).unwrap();
+                        notify(&c.conn, c.win_server, c.win_vime);
+                    }
+                } else if is_amend_key && !c.vime_state.is_active_for(&ic) {
+                    if key_event.is_press {
+                        match c.last_commits.take(&ic) {
+                            Some(commit) => {
+                                let client = get_client(&c.conn, server.get_client_window(&ic));
+                                let text = Some(commit.text.clone());
+                                let mode = SessionMode::Normal;
+                                start_session(&c, ic, mode, client, text, Some(commit));
+                            }
+                            None => log::debug!("forward: nothing to amend"),
+                        }
+                    }
+                } else if is_undo_key && !c.vime_state.is_active_for(&ic) {
+                    if key_event.is_press {
+                        match c.last_commits.take(&ic) {
+                            Some(commit) => erase_commit(&c, server, &commit),
+                            None => log::debug!("forward: nothing to undo"),
+                        }
+                    }
+                } else if is_upstream_im_key && !c.vime_state.is_active_for(&ic) {
+                    if key_event.is_press {
+                        c.chan_passthru.tx.send(Message::NextUpstream).unwrap();
+                        notify(&c.conn, c.win_server, c.win_passthru);
+                    }
+                } else if is_recall_key && !c.vime_state.is_active_for(&ic) {
+                    if key_event.is_press {
+                        let client = get_client(&c.conn, server.get_client_window(&ic));
+                        match history::recall(&client.class) {
+                            Some(text) => {
+                                let mode = SessionMode::Normal;
+                                start_session(&c, ic, mode, client, Some(text), None);
+                            }
+                            None => log::debug!("forward: nothing to recall"),
+                        }
+                    }
+                } else if let Some(&(mode, _, _)) = trigger {
+                    if key_event.is_press {
+                        if !c.vime_state.is_active_for(&ic) {
+                            let client = get_client(&c.conn, server.get_client_window(&ic));
+                            let mode = mode.for_client(&client);
+                            start_session(&c, ic, mode, client, None, None);
                         } else {
-                            c.chan_vime.tx.send(Message::CancelPreedit).unwrap();
+                            c.vime_state.inactivate(&ic);
+                            untrack_client_windows(&c);
+                            let msg = Message::CancelSession(ic.clone());
+                            c.chan_vime.tx.send(msg).unwrap();
+                            notify(&c.conn, c.win_server, c.win_vime);
 
-                            let ic = c.current_ic.borrow().clone().unwrap();
-                            c.chan_passthru.tx.send(Message::StartPreedit(ic)).unwrap();
-                            notify(&c.conn, c.win_server, c.win_passthru);
+                            start_preedit(&c, server, ic);
                         }
                     }
+                } else if c.vime_state.is_active_for(&ic) {
+                    // NOTE: The PTY keeps the input until the editor reads it
+                    let mut key_input = c.key_input.borrow_mut();
+                    let bytes = key_input.as_mut().and_then(|k| k.translate(&key_event));
+                    if let Some(bytes) = bytes {
+                        c.chan_vime.tx.send(Message::Input(ic, bytes)).unwrap();
+                        notify(&c.conn, c.win_server, c.win_vime);
+                    }
                 } else {
-                    let target = if c.vime_state.is_active() {
-                        c.win_vime
-                    } else {
-                        c.win_passthru
-                    };
-
-                    key_event.event = target;
-                    key_event.child = Window::none();
-                    let synth_event = key_event.to_generic();
-
-                    c.conn.send_request(&xcb::x::SendEvent {
-                        event: &synth_event,
-                        destination: xcb::x::SendEventDest::Window(target),
-                        propagate: false,
-                        event_mask: xcb::x::EventMask::empty(),
-                    });
-                    c.conn.flush().unwrap();
+                    // The caret may have moved away from the end of the last commit
+                    if key_event.is_press && !modifier_keycodes.contains(&key_event.detail) {
+                        c.last_commits.forget(&ic);
+                    }
+                    forward_key(&c, c.win_passthru, key_event);
                 }
             })
         },
@@ -186,6 +481,9 @@ pub fn main(chan_vime: Channel, chan_passthru: Channel) {
 
                 let (win_x, win_y) = absolute_position(&c.conn, win);
                 let (x, y) = (win_x + pos_x, win_y + pos_y);
+                c.spot.set((x, y));
+                c.spot_window.set(win);
+                c.spot_offset.set((pos_x, pos_y));
 
                 c.conn.send_request(&xcb::x::ConfigureWindow {
                     window: c.win_passthru,
@@ -196,17 +494,9 @@ pub fn main(chan_vime: Channel, chan_passthru: Channel) {
                     ],
                 });
 
-                let (x, y) = adjust_vime_window_position(&c.conn, x, y, c.win_vime);
-                c.conn.send_request(&xcb::x::ConfigureWindow {
-                    window: c.win_vime,
-                    value_list: &[
-                        xcb::x::ConfigWindow::X(x as i32),
-                        xcb::x::ConfigWindow::Y(y as i32),
-                        xcb::x::ConfigWindow::StackMode(xcb::x::StackMode::Above),
-                    ],
-                });
-
-                c.conn.flush().unwrap();
+                if let Some(ic) = c.current_ic.borrow().clone() {
+                    move_vime_window(&c, &ic);
+                }
             })
         },
     };
@@ -217,38 +507,249 @@ pub fn main(chan_vime: Channel, chan_passthru: Channel) {
         c.win_server,
         IM_NAME,
         true,
+        vime_config::CONFIG.passthrough_preedit,
         im_server_callbacks,
     );
 
+    let mut clipboard = {
+        let setup = c.conn.get_setup();
+        let screen = setup.roots().nth(c.default_screen as usize).unwrap();
+        Clipboard::new(&c.conn, screen)
+    };
+
+    let scratchpad_key = grab_scratchpad_key(&c);
+    let mut is_scratchpad_open = false;
+
     loop {
         let event = c.conn.wait_for_event().unwrap();
 
+        if clipboard.process_event(&c.conn, &event) {
+            continue;
+        }
+
+        // NOTE: The vime backend selects Expose on the session windows with this connection
+        if let xcb::Event::X(xcb::x::Event::Expose(ev)) = &event {
+            if ev.count() == 0 {
+                c.chan_vime.tx.send(Message::Exposed(ev.window())).unwrap();
+                notify(&c.conn, c.win_server, c.win_vime);
+            }
+            continue;
+        }
+
+        // NOTE: the key events on the root window come only from the grab
+        if let xcb::Event::X(xcb::x::Event::KeyPress(ev)) = &event {
+            if ev.event() == c.root && Some(ev.detail()) == scratchpad_key {
+                if is_scratchpad_open {
+                    c.chan_vime.tx.send(Message::CancelScratchpad).unwrap();
+                } else {
+                    c.chan_vime.tx.send(Message::StartScratchpad).unwrap();
+                }
+                notify(&c.conn, c.win_server, c.win_vime);
+                is_scratchpad_open = !is_scratchpad_open;
+                continue;
+            }
+        }
+
+        // The client window has been moved or resized while a session is open
+        if let xcb::Event::X(xcb::x::Event::ConfigureNotify(ev)) = &event {
+            let tracked_windows = c.tracked_windows.borrow().clone();
+            if tracked_windows.iter().any(|&(win, _)| win == ev.window()) {
+                let (win_x, win_y) = absolute_position(&c.conn, c.spot_window.get());
+                let (pos_x, pos_y) = c.spot_offset.get();
+                c.spot.set((win_x + pos_x, win_y + pos_y));
+
+                if let Some(ic) = c.current_ic.borrow().clone() {
+                    move_vime_window(&c, &ic);
+                }
+            }
+        }
+
         if let Some(win) = is_vime_message(&c.conn, &event) {
+            let msg = if win == c.win_vime {
+                c.chan_vime.rx.recv().unwrap()
+            } else if win == c.win_passthru {
+                c.chan_passthru.rx.recv().unwrap()
+            } else if win == c.win_server {
+                c.chan_expander.rx.recv().unwrap()
+            } else {
+                continue;
+            };
+
+            // NOTE: The IC may have been destroyed while the message was on the way
+            if msg.ic().is_some_and(|ic| !server.is_live(ic)) {
+                log::debug!("the message for a destroyed IC is discarded");
+                continue;
+            }
+
             if win == c.win_vime {
-                match c.chan_vime.rx.recv().unwrap() {
-                    Message::EditResult(ic, Some(text)) => {
-                        server.commit_string(ic, &text);
+                match msg {
+                    Message::SessionWindow(ic, window) => {
+                        c.vime_state.set_window(&ic, window);
+                        set_transient_for(&c.conn, window, c.vime_state.client_window(&ic));
+                        move_vime_window(&c, &ic);
                     }
-                    Message::EditResult(_, None) => {}
-                    _ => unreachable!(),
-                }
 
-                c.vime_state.inactivate();
+                    // NOTE: The session has been dropped if the IC was destroyed
+                    Message::EditResult(ic, _) if !c.vime_state.is_active_for(&ic) => {
+                        log::debug!("the result of a dropped session is discarded");
+                    }
+                    Message::EditResult(ic, commit) => {
+                        let mut target = ic.clone();
+                        if c.vime_state.is_pinned(&ic) && vime_config::CONFIG.pin_commit_to_focused
+                        {
+                            if let Some(focused_ic) = c.current_ic.borrow().clone() {
+                                target = focused_ic;
+                            }
+                        }
 
-                if let Some(ic) = c.current_ic.borrow().clone() {
-                    c.chan_passthru.tx.send(Message::StartPreedit(ic)).unwrap();
-                    notify(&c.conn, c.win_server, c.win_passthru);
+                        let amend = c.vime_state.take_amend(&ic);
+                        let mode = c.vime_state.mode(&ic);
+                        if let Some(commit) = commit {
+                            if let Some(amend) = &amend {
+                                erase_commit(&c, &server, amend);
+                            }
+                            server.commit_string(target.clone(), &commit.text);
+                            send_keys(&c, &server, target.clone(), XK_LEFT, commit.caret_back);
+
+                            // NOTE: A pinned session may commit to another application
+                            let class = match c.vime_state.client_class(&ic) {
+                                Some(class) if target == ic => class,
+                                _ => get_client(&c.conn, server.get_client_window(&target)).class,
+                            };
+                            history::record(&class, &commit.text);
+
+                            // NOTE: lines committed in the line-by-line mode can't be amended
+                            if mode == Some(SessionMode::Normal) {
+                                c.last_commits.set(LastCommit {
+                                    ic: target,
+                                    text: commit.text,
+                                    caret_back: commit.caret_back,
+                                });
+                            }
+                        } else if let Some(amend) = amend {
+                            // The amend was cancelled, so the old text is still there
+                            c.last_commits.set(amend);
+                        }
+
+                        c.vime_state.inactivate(&ic);
+                        if c.current_ic.borrow().as_ref() == Some(&ic) {
+                            untrack_client_windows(&c);
+                        }
+
+                        if let Some(ic) = c.current_ic.borrow().clone() {
+                            if !c.vime_state.is_active_for(&ic) {
+                                start_preedit(&c, &server, ic);
+                            }
+                        }
+                    }
+
+                    Message::CommitLine(ic, _) if !c.vime_state.is_active_for(&ic) => {
+                        log::debug!("the line of a dropped session is discarded");
+                    }
+                    Message::CommitLine(ic, line) => {
+                        c.last_commits.forget(&ic);
+                        if !line.is_empty() {
+                            server.commit_string(ic.clone(), &line);
+                            let class = c.vime_state.client_class(&ic).unwrap_or_default();
+                            history::record(&class, &line);
+                        }
+                        if vime_config::CONFIG.line_mode_send_return {
+                            send_keys(&c, &server, ic, XK_RETURN, 1);
+                        }
+                    }
+
+                    Message::Resized(ic) => {
+                        move_vime_window(&c, &ic);
+                    }
+
+                    Message::ScratchpadWindow(window) => {
+                        set_transient_for(&c.conn, window, None);
+                        move_scratchpad_window(&c, window);
+                    }
+
+                    Message::ScratchpadResult(commit) => {
+                        is_scratchpad_open = false;
+                        if let Some(commit) = commit.filter(|commit| !commit.text.is_empty()) {
+                            log::debug!("scratchpad: set the text to the clipboard");
+                            history::record("", &commit.text);
+                            clipboard.set_text(&c.conn, commit.text);
+                        }
+                    }
+
+                    _ => unreachable!(),
                 }
             } else if win == c.win_passthru {
-                match c.chan_passthru.rx.recv().unwrap() {
-                    Message::EditResult(ic, Some(text)) => {
-                        server.commit_string(ic, &text);
+                match msg {
+                    Message::EditResult(ic, Some(commit)) => {
+                        let class = || get_client(&c.conn, server.get_client_window(&ic)).class;
+                        let mut expander = c.expander.borrow_mut();
+                        let abbreviation = expander
+                            .as_mut()
+                            .and_then(|expander| expander.feed_text(&ic, &commit.text, class));
+                        drop(expander);
+
+                        server.commit_string(ic.clone(), &commit.text);
+                        if let Some(abbreviation) = abbreviation {
+                            expand(&c, &server, ic, abbreviation);
+                        }
                     }
 
                     Message::ForwardEvent(ic, mut key_event) => {
                         key_event.event = server.get_client_window(&ic);
                         key_event.child = Window::none();
-                        server.forward_event(ic, key_event);
+
+                        let class = || get_client(&c.conn, key_event.event).class;
+                        let mut expander = c.expander.borrow_mut();
+                        let abbreviation = expander
+                            .as_mut()
+                            .and_then(|expander| expander.feed_key(&ic, &key_event, class));
+                        drop(expander);
+
+                        server.forward_event(ic.clone(), key_event);
+                        if let Some(abbreviation) = abbreviation {
+                            expand(&c, &server, ic, abbreviation);
+                        }
+                    }
+
+                    // NOTE: The clients in the over-the-spot style can't draw the preedit string
+                    Message::PreeditStart(ic) => {
+                        if server.has_preedit_callbacks(&ic) {
+                            server.preedit_start(ic);
+                        }
+                    }
+                    Message::PreeditDraw(ic, preedit) => {
+                        if server.has_preedit_callbacks(&ic) {
+                            let draw = PreeditDraw {
+                                caret: preedit.caret,
+                                chg_first: preedit.chg_first,
+                                chg_length: preedit.chg_length,
+                                text: &preedit.text,
+                                feedback: &preedit.feedback,
+                            };
+                            server.preedit_draw(ic, &draw);
+                        }
+                    }
+                    Message::PreeditDone(ic) => {
+                        if server.has_preedit_callbacks(&ic) {
+                            server.preedit_done(ic);
+                        }
+                    }
+
+                    _ => unreachable!(),
+                }
+            } else {
+                match msg {
+                    Message::Expanded(ic, erase, text) => {
+                        let mut expander = c.expander.borrow_mut();
+                        let is_pending = expander.as_mut().is_some_and(|e| e.take_pending(&ic));
+                        drop(expander);
+
+                        // NOTE: The abbreviation is left as is if the command fails
+                        if !is_pending {
+                            log::debug!("expander: the text has changed during the command");
+                        } else if let Some(text) = text {
+                            replace_abbreviation(&c, &server, ic, erase, text);
+                        }
                     }
 
                     _ => unreachable!(),
@@ -261,6 +762,369 @@ pub fn main(chan_vime: Channel, chan_passthru: Channel) {
     }
 }
 
+/// Starts passing the keys from the IC through to the upstream IM.
+fn start_preedit(c: &Context, server: &ImeServer, ic: Ic) {
+    let config = &vime_config::CONFIG;
+
+    // NOTE: WM_CLASS is looked up only if any profile pins an upstream IM
+    let mut upstream = None;
+    if config.profiles.values().any(|p| p.upstream_im.is_some()) {
+        let client = get_client(&c.conn, server.get_client_window(&ic));
+        let profile = config.profile(&client.class);
+        upstream = profile.and_then(|p| p.upstream_im.clone());
+    }
+
+    let msg = Message::StartPreedit(ic, upstream);
+    c.chan_passthru.tx.send(msg).unwrap();
+    notify(&c.conn, c.win_server, c.win_passthru);
+}
+
+/// Starts a vime session for the IC in place of the passthrough preedit.
+fn start_session(
+    c: &Context,
+    ic: Ic,
+    mode: SessionMode,
+    client: Client,
+    text: Option<String>,
+    amend: Option<LastCommit>,
+) {
+    c.chan_passthru.tx.send(Message::CancelPreedit).unwrap();
+    notify(&c.conn, c.win_server, c.win_passthru);
+    if let Some(expander) = c.expander.borrow_mut().as_mut() {
+        expander.reset();
+    }
+
+    c.vime_state.start(ic.clone(), mode, client, text, amend);
+
+    let msg = c.vime_state.start_message(&ic).unwrap();
+    c.chan_vime.tx.send(msg).unwrap();
+    notify(&c.conn, c.win_server, c.win_vime);
+}
+
+/// Deletes the text of the commit from the client, assuming the caret is where it was left.
+fn erase_commit(c: &Context, server: &ImeServer, commit: &LastCommit) {
+    // Move the caret to the end of the text, and delete it by grapheme clusters
+    // as most text fields do for BackSpace
+    send_keys(c, server, commit.ic.clone(), XK_RIGHT, commit.caret_back);
+    let count = commit.text.graphemes(true).count();
+    send_keys(c, server, commit.ic.clone(), XK_BACKSPACE, count);
+}
+
+/// Replaces the abbreviation which has just been typed in the client with its expansion.
+/// The command of the expansion runs on a worker thread, and its output comes back
+/// as `Message::Expanded`.
+fn expand(c: &Context, server: &ImeServer, ic: Ic, abbreviation: Abbreviation) {
+    log::debug!("expander: {:?}", abbreviation.expansion.trigger);
+    let Abbreviation { erase, expansion } = abbreviation;
+
+    if expansion.command.is_empty() {
+        replace_abbreviation(c, server, ic, erase, expansion.text);
+        return;
+    }
+
+    let tx = c.chan_expander.tx.clone();
+    let conn = c.conn.clone();
+    let win_server = c.win_server;
+    std::thread::spawn(move || {
+        let text = expander::run_command(&expansion.command);
+        tx.send(Message::Expanded(ic, erase, text)).unwrap();
+        notify(&conn, win_server, win_server);
+    });
+}
+
+/// Deletes the abbreviation before the caret, and commits the text of the expansion.
+fn replace_abbreviation(c: &Context, server: &ImeServer, ic: Ic, erase: usize, text: String) {
+    send_keys(c, server, ic.clone(), XK_BACKSPACE, erase);
+    server.commit_string(ic.clone(), &text);
+
+    // The expansion can be amended or undone as a commit
+    c.last_commits.set(LastCommit {
+        ic,
+        text,
+        caret_back: 0,
+    });
+}
+
+/// Grabs the key to open a scratchpad if configured, and returns the keycode.
+fn grab_scratchpad_key(c: &Context) -> Option<u8> {
+    let config = &vime_config::CONFIG;
+    if config.scratchpad_key_keycode == 0 || c.win_vime.is_none() {
+        return None;
+    }
+
+    // Grab the key regardless of CapsLock and NumLock
+    let state = xcb::x::ModMask::from_bits_truncate(config.scratchpad_key_state);
+    let locks = [
+        xcb::x::ModMask::empty(),
+        xcb::x::ModMask::LOCK,
+        xcb::x::ModMask::N2,
+        xcb::x::ModMask::LOCK | xcb::x::ModMask::N2,
+    ];
+    for lock in locks {
+        c.conn.send_request(&xcb::x::GrabKey {
+            owner_events: false,
+            grab_window: c.root,
+            modifiers: state | lock,
+            key: config.scratchpad_key_keycode,
+            pointer_mode: xcb::x::GrabMode::Async,
+            keyboard_mode: xcb::x::GrabMode::Async,
+        });
+    }
+    c.conn.flush().unwrap();
+
+    Some(config.scratchpad_key_keycode)
+}
+
+/// Sends the key event to the passthrough backend window.
+fn forward_key(c: &Context, target: Window, mut key_event: KeyEvent) {
+    key_event.event = target;
+    key_event.child = Window::none();
+    let synth_event = key_event.to_generic();
+
+    c.conn.send_request(&xcb::x::SendEvent {
+        event: &synth_event,
+        destination: xcb::x::SendEventDest::Window(target),
+        propagate: false,
+        event_mask: xcb::x::EventMask::empty(),
+    });
+    c.conn.flush().unwrap();
+}
+
+/// Synthesizes presses and releases of the key to the client.
+fn send_keys(c: &Context, server: &ImeServer, ic: Ic, keysym: xcb::x::Keysym, count: usize) {
+    if count == 0 {
+        return;
+    }
+
+    let Some(keycode) = keyboard::keysym_to_keycode(&c.conn, keysym) else {
+        log::warn!("no keycode for keysym {:#x}", keysym);
+        return;
+    };
+
+    let press = KeyEvent {
+        is_press: true,
+        detail: keycode,
+        time: xcb::x::CURRENT_TIME,
+        root: c.root,
+        event: server.get_client_window(&ic),
+        child: Window::none(),
+        root_x: 0,
+        root_y: 0,
+        event_x: 0,
+        event_y: 0,
+        state: xcb::x::KeyButMask::empty(),
+        same_screen: true,
+    };
+    let release = KeyEvent {
+        is_press: false,
+        ..press.clone()
+    };
+
+    for _ in 0..count {
+        server.forward_event(ic.clone(), press.clone());
+        server.forward_event(ic.clone(), release.clone());
+    }
+}
+
+/// Moves the window of the session to the spot if the IC is the current one.
+fn move_vime_window(c: &Context, ic: &Ic) {
+    if c.current_ic.borrow().as_ref() != Some(ic) {
+        return;
+    }
+    let Some(window) = c.vime_state.window(ic) else { return };
+    let Some(placement) = c.vime_state.placement(ic) else { return };
+
+    track_client_window(c);
+    place_vime_window(c, window, placement);
+}
+
+/// Starts watching ConfigureNotify of the spot window and its ancestors
+/// so that the session window follows the client when it is moved or scrolled.
+fn track_client_window(c: &Context) {
+    let spot_window = c.spot_window.get();
+    let first = c.tracked_windows.borrow().first().map(|&(win, _)| win);
+    if spot_window.is_none() || first == Some(spot_window) {
+        return;
+    }
+    untrack_client_windows(c);
+
+    let mut tracked = Vec::new();
+    let mut win = spot_window;
+    while !win.is_none() && win != c.root {
+        // NOTE: The mask is per client, and xcb-imdkit shares the connection,
+        // so StructureNotify is added to the mask selected so far.
+        let request = xcb::x::GetWindowAttributes { window: win };
+        let cookie = c.conn.send_request(&request);
+        let Ok(attributes) = c.conn.wait_for_reply(cookie) else { break };
+        let mask = attributes.your_event_mask();
+        let new_mask = mask | xcb::x::EventMask::STRUCTURE_NOTIFY;
+        c.conn.send_request(&xcb::x::ChangeWindowAttributes {
+            window: win,
+            value_list: &[xcb::x::Cw::EventMask(new_mask)],
+        });
+        tracked.push((win, mask));
+
+        let cookie = c.conn.send_request(&xcb::x::QueryTree { window: win });
+        let Ok(reply) = c.conn.wait_for_reply(cookie) else { break };
+        win = reply.parent();
+    }
+    c.conn.flush().unwrap();
+
+    log::debug!("tracking {} client windows", tracked.len());
+    *c.tracked_windows.borrow_mut() = tracked;
+}
+
+/// Stops watching the client windows, and restores their event masks.
+fn untrack_client_windows(c: &Context) {
+    let tracked = std::mem::take(&mut *c.tracked_windows.borrow_mut());
+    if tracked.is_empty() {
+        return;
+    }
+
+    // NOTE: The windows may have been destroyed, and then the errors are ignored
+    for (win, mask) in tracked {
+        c.conn.send_request(&xcb::x::ChangeWindowAttributes {
+            window: win,
+            value_list: &[xcb::x::Cw::EventMask(mask)],
+        });
+    }
+    c.conn.flush().unwrap();
+}
+
+/// Moves the window of the scratchpad to the pointer.
+fn move_scratchpad_window(c: &Context, window: Window) {
+    place_vime_window(c, window, Placement::Pointer);
+}
+
+fn place_vime_window(c: &Context, window: Window, placement: Placement) {
+    let monitors = get_monitors(&c.conn, window);
+    let rects: Vec<Rect> = monitors.iter().map(|m| m.rect).collect();
+
+    let (spot_x, spot_y) = c.spot.get();
+    let mut surroundings = Surroundings {
+        monitors: &rects,
+        spot: (spot_x as i32, spot_y as i32),
+        ..Default::default()
+    };
+    let spot_window = c.spot_window.get();
+    match placement {
+        Placement::CoverField => {
+            surroundings.area = window_rect(&c.conn, spot_window);
+        }
+        Placement::DockBottom => {
+            let toplevel = toplevel_window(&c.conn, c.root, spot_window);
+            surroundings.toplevel = window_rect(&c.conn, toplevel);
+        }
+        Placement::Pointer => {
+            let request = xcb::x::QueryPointer { window: c.root };
+            let cookie = c.conn.send_request(&request);
+            let reply = c.conn.wait_for_reply(cookie).unwrap();
+            surroundings.pointer = (reply.root_x() as i32, reply.root_y() as i32);
+        }
+        _ => {}
+    }
+
+    let cookie = c.conn.send_request(&xcb::x::GetGeometry {
+        drawable: xcb::x::Drawable::Window(window),
+    });
+    let reply = c.conn.wait_for_reply(cookie).unwrap();
+    let size = (reply.width() as i32, reply.height() as i32);
+
+    let (x, y) = placement::place(placement, &surroundings, size);
+
+    if vime_config::CONFIG.dpi_scaling {
+        if let Some(i) = placement::monitor_at(&rects, (x, y)) {
+            scale_vime_window(c, window, &monitors[i]);
+        }
+    }
+
+    // A managed window is placed by the window manager
+    if !vime_config::CONFIG.override_redirect {
+        return;
+    }
+
+    c.conn.send_request(&xcb::x::ConfigureWindow {
+        window,
+        value_list: &[
+            xcb::x::ConfigWindow::X(x),
+            xcb::x::ConfigWindow::Y(y),
+            xcb::x::ConfigWindow::StackMode(xcb::x::StackMode::Above),
+        ],
+    });
+    c.conn.flush().unwrap();
+}
+
+/// Sets WM_TRANSIENT_FOR of the vime window to the top-level window of the client,
+/// or deletes it if there is no client (e.g. for the scratchpad).
+fn set_transient_for(conn: &xcb::Connection, window: Window, client_window: Option<Window>) {
+    match client_window {
+        Some(client_window) => {
+            conn.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Replace,
+                window,
+                property: xcb::x::ATOM_WM_TRANSIENT_FOR,
+                r#type: xcb::x::ATOM_WINDOW,
+                data: &[client_window],
+            });
+        }
+        None => {
+            conn.send_request(&xcb::x::DeleteProperty {
+                window,
+                property: xcb::x::ATOM_WM_TRANSIENT_FOR,
+            });
+        }
+    }
+    conn.flush().unwrap();
+}
+
+/// Gets the class, the title and the top-level window of the client.
+fn get_client(conn: &xcb::Connection, win: Window) -> Client {
+    let net_wm_name = {
+        let cookie = conn.send_request(&xcb::x::InternAtom {
+            only_if_exists: false,
+            name: b"_NET_WM_NAME",
+        });
+        conn.wait_for_reply(cookie).unwrap().atom()
+    };
+
+    let get_property = |win: Window, property: xcb::x::Atom| {
+        let cookie = conn.send_request(&xcb::x::GetProperty {
+            delete: false,
+            window: win,
+            property,
+            r#type: xcb::x::ATOM_ANY,
+            long_offset: 0,
+            long_length: 1024,
+        });
+        let reply = conn.wait_for_reply(cookie).ok()?;
+        let value: &[u8] = reply.value();
+        (!value.is_empty()).then(|| String::from_utf8_lossy(value).into_owned())
+    };
+
+    // Find the top-level window, which has WM_CLASS
+    let mut win = win;
+    while !win.is_none() {
+        if let Some(class) = get_property(win, xcb::x::ATOM_WM_CLASS) {
+            // WM_CLASS consists of the instance name and the class name
+            let class = class.split('\0').nth(1).unwrap_or_default().to_owned();
+            let title = get_property(win, net_wm_name)
+                .or_else(|| get_property(win, xcb::x::ATOM_WM_NAME))
+                .unwrap_or_default();
+            return Client {
+                class,
+                title,
+                window: Some(win),
+            };
+        }
+
+        let cookie = conn.send_request(&xcb::x::QueryTree { window: win });
+        let Ok(reply) = conn.wait_for_reply(cookie) else { break };
+        win = reply.parent();
+    }
+
+    Client::default()
+}
+
 /// Calculates the absolute position of the upper-left corner of a window.
 fn absolute_position(conn: &xcb::Connection, win: Window) -> (i16, i16) {
     let mut win = win;
@@ -284,73 +1148,107 @@ fn absolute_position(conn: &xcb::Connection, win: Window) -> (i16, i16) {
     (abs_x, abs_y)
 }
 
-/// Calculates a better position of the vime window so that it won't go out of the screen,
-/// and returns the adjusted position.
-fn adjust_vime_window_position(
-    conn: &xcb::Connection,
-    x: i16,
-    y: i16,
-    win_vime: Window,
-) -> (i16, i16) {
+/// Returns the rectangle of a window in the root window coordinates.
+fn window_rect(conn: &xcb::Connection, win: Window) -> Option<Rect> {
+    if win.is_none() {
+        return None;
+    }
+    let cookie = conn.send_request(&xcb::x::GetGeometry {
+        drawable: xcb::x::Drawable::Window(win),
+    });
+    let reply = conn.wait_for_reply(cookie).ok()?;
+
+    let (x, y) = absolute_position(conn, win);
+    let (w, h) = (reply.width() as i32, reply.height() as i32);
+    Some(Rect::new(x as i32, y as i32, w, h))
+}
+
+/// Returns the ancestor of the window which is a child of the root window.
+fn toplevel_window(conn: &xcb::Connection, root: Window, win: Window) -> Window {
+    let mut win = win;
+    while !win.is_none() {
+        let cookie = conn.send_request(&xcb::x::QueryTree { window: win });
+        let Ok(reply) = conn.wait_for_reply(cookie) else { break };
+        if reply.parent() == root || reply.parent().is_none() {
+            break;
+        }
+        win = reply.parent();
+    }
+    win
+}
+
+struct Monitor {
+    rect: Rect,
+    // physical width, which is 0 if unknown
+    width_mm: u32,
+}
+
+fn get_monitors(conn: &xcb::Connection, win_vime: Window) -> Vec<Monitor> {
     let cookie = conn.send_request(&xcb::randr::GetMonitors {
         window: win_vime,
         get_active: true,
     });
     let reply = conn.wait_for_reply(cookie).unwrap();
 
-    let mon_info = reply
+    reply
         .monitors()
-        .find(|info| {
-            let x_in = info.x() <= x && x as i32 <= info.x() as i32 + info.width() as i32;
-            let y_in = info.y() <= y && y as i32 <= info.y() as i32 + info.height() as i32;
-            x_in && y_in
+        .map(|info| Monitor {
+            rect: Rect::new(
+                info.x() as i32,
+                info.y() as i32,
+                info.width() as i32,
+                info.height() as i32,
+            ),
+            width_mm: info.width_in_millimeters(),
         })
-        .or_else(|| reply.monitors().next());
+        .collect()
+}
 
-    let Some(mon_info) = mon_info else { return (x, y) };
+/// Changes the font size of the vime window for the DPI of the monitor.
+fn scale_vime_window(c: &Context, window: Window, monitor: &Monitor) {
+    let config = &vime_config::CONFIG;
 
-    #[rustfmt::skip]
-    struct Rect { x: i32, y: i32, w: i32, h: i32 }
+    let Some(dpi) = monitor_dpi(&c.conn, c.root, monitor) else { return };
+    let font_size = (config.font_size as f64 * dpi / config.base_dpi as f64).round() as u32;
+    let font_size = font_size.max(1);
 
-    #[rustfmt::skip]
-    impl Rect {
-        fn t(&self) -> i32 { self.y }
-        fn b(&self) -> i32 { self.y + self.h }
-        fn l(&self) -> i32 { self.x }
-        fn r(&self) -> i32 { self.x + self.w }
+    let current = c.font_sizes.borrow().get(&window).copied();
+    if current.unwrap_or(config.font_size) == font_size {
+        return;
     }
 
-    let mon = Rect {
-        x: mon_info.x() as i32,
-        y: mon_info.y() as i32,
-        w: mon_info.width() as i32,
-        h: mon_info.height() as i32,
-    };
-
-    let cookie = conn.send_request(&xcb::x::GetGeometry {
-        drawable: xcb::x::Drawable::Window(win_vime),
-    });
-    let reply = conn.wait_for_reply(cookie).unwrap();
-
-    let mut win = Rect {
-        x: x as i32,
-        y: y as i32,
-        w: reply.width() as i32,
-        h: reply.height() as i32,
-    };
+    log::debug!("DPI: {:.1}, font size: {}", dpi, font_size);
+    c.font_sizes.borrow_mut().insert(window, font_size);
+    c.chan_vime
+        .tx
+        .send(Message::FontSize(window, font_size))
+        .unwrap();
+    notify(&c.conn, c.win_server, c.win_vime);
+}
 
-    if win.t() < mon.t() {
-        win.y = mon.t();
-    }
-    if win.l() < mon.l() {
-        win.x = mon.l();
-    }
-    if mon.b() < win.b() {
-        win.y = (y as i32) - win.h - 40;
-    }
-    if mon.r() < win.r() {
-        win.x = mon.r() - win.w;
+/// Calculates the DPI of the monitor from its physical size.
+/// Falls back to `Xft.dpi` in the resource database if the size is unknown.
+fn monitor_dpi(conn: &xcb::Connection, root: Window, monitor: &Monitor) -> Option<f64> {
+    if monitor.width_mm > 0 {
+        return Some(monitor.rect.w as f64 * 25.4 / monitor.width_mm as f64);
     }
 
-    (win.x as i16, win.y as i16)
+    let cookie = conn.send_request(&xcb::x::GetProperty {
+        delete: false,
+        window: root,
+        property: xcb::x::ATOM_RESOURCE_MANAGER,
+        r#type: xcb::x::ATOM_STRING,
+        long_offset: 0,
+        long_length: u32::MAX / 4,
+    });
+    let reply = conn.wait_for_reply(cookie).ok()?;
+    let resources = String::from_utf8_lossy(reply.value());
+
+    resources.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim() != "Xft.dpi" {
+            return None;
+        }
+        value.trim().parse::<f64>().ok().filter(|&dpi| dpi > 0.0)
+    })
 }
@@ -0,0 +1,237 @@
+use xcb_imdkit::KeyEvent;
+use xkbcommon::xkb;
+use xkbcommon::xkb::keysyms as ks;
+
+/// Translates the key events from XIM clients into the input to the editor
+/// with the XKB keymap of the X server, including the layout group and compose sequences.
+pub struct KeyInput {
+    state: xkb::State,
+    compose: Option<xkb::compose::State>,
+}
+
+impl KeyInput {
+    pub fn new(conn: &xcb::Connection) -> Option<Self> {
+        let (context, state) = keyboard_state(conn)?;
+        let compose = crate::compose::new_state(&context);
+        Some(Self { state, compose })
+    }
+
+    /// Returns the bytes to write to the terminal for the key event, if any.
+    pub fn translate(&mut self, key_event: &KeyEvent) -> Option<Vec<u8>> {
+        if !key_event.is_press {
+            return None;
+        }
+
+        let keysym = update_state(&mut self.state, key_event);
+        let keycode = key_event.detail as xkb::Keycode;
+
+        if let Some(compose) = &mut self.compose {
+            compose.feed(keysym);
+            match compose.status() {
+                xkb::compose::Status::Composing => return None,
+                xkb::compose::Status::Cancelled => {
+                    compose.reset();
+                    return None;
+                }
+                xkb::compose::Status::Composed => {
+                    let text = compose.utf8();
+                    compose.reset();
+                    return text.map(String::into_bytes);
+                }
+                xkb::compose::Status::Nothing => {}
+            }
+        }
+
+        let bits = key_event.state.bits();
+        let modifiers = Modifiers {
+            shift: bits & 0x1 != 0,
+            ctrl: bits & 0x4 != 0,
+            alt: bits & 0x8 != 0,
+        };
+        encode(keysym, &self.state.key_get_utf8(keycode), modifiers)
+    }
+}
+
+/// Returns the XKB context, and a state with the keymap of the X server.
+pub fn keyboard_state(conn: &xcb::Connection) -> Option<(xkb::Context, xkb::State)> {
+    let (mut major, mut minor, mut base_event, mut base_error) = (0, 0, 0, 0);
+    let ok = xkb::x11::setup_xkb_extension(
+        conn,
+        xkb::x11::MIN_MAJOR_XKB_VERSION,
+        xkb::x11::MIN_MINOR_XKB_VERSION,
+        xkb::x11::SetupXkbExtensionFlags::NoFlags,
+        &mut major,
+        &mut minor,
+        &mut base_event,
+        &mut base_error,
+    );
+    if !ok {
+        log::warn!("XKB is not available");
+        return None;
+    }
+
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let device_id = xkb::x11::get_core_keyboard_device_id(conn);
+    let keymap =
+        xkb::x11::keymap_new_from_device(&context, conn, device_id, xkb::KEYMAP_COMPILE_NO_FLAGS);
+    let state = xkb::State::new(&keymap);
+    Some((context, state))
+}
+
+/// Updates the state with the modifiers of the key event, and returns the keysym of the key.
+pub fn update_state(state: &mut xkb::State, key_event: &KeyEvent) -> xkb::Keysym {
+    // The state of a key event has the modifiers in the lower 8 bits,
+    // and the layout group in bits 13-14.
+    let bits = key_event.state.bits();
+    let mods = bits & 0xFF;
+    let group = (bits >> 13) & 0x3;
+    state.update_mask(mods, 0, 0, 0, 0, group);
+
+    state.key_get_one_sym(key_event.detail as xkb::Keycode)
+}
+
+#[derive(Clone, Copy)]
+struct Modifiers {
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+}
+
+impl Modifiers {
+    /// The modifier parameter of xterm (e.g. 5 for Ctrl)
+    fn param(&self) -> u8 {
+        1 + self.shift as u8 + self.alt as u8 * 2 + self.ctrl as u8 * 4
+    }
+}
+
+/// Encodes a key as xterm does.
+fn encode(keysym: xkb::Keysym, text: &str, modifiers: Modifiers) -> Option<Vec<u8>> {
+    let modified = modifiers.param() > 1;
+
+    // CSI 1 ; m X for the cursor keys and Home / End
+    let letter = match keysym {
+        ks::KEY_Up => Some('A'),
+        ks::KEY_Down => Some('B'),
+        ks::KEY_Right => Some('C'),
+        ks::KEY_Left => Some('D'),
+        ks::KEY_Home => Some('H'),
+        ks::KEY_End => Some('F'),
+        _ => None,
+    };
+    if let Some(letter) = letter {
+        let seq = if modified {
+            format!("\x1b[1;{}{}", modifiers.param(), letter)
+        } else {
+            format!("\x1b[{}", letter)
+        };
+        return Some(seq.into_bytes());
+    }
+
+    // CSI n ; m ~ for the editing keys and F5-F12
+    let number = match keysym {
+        ks::KEY_Insert => Some(2),
+        ks::KEY_Delete => Some(3),
+        ks::KEY_Prior => Some(5),
+        ks::KEY_Next => Some(6),
+        ks::KEY_F5 => Some(15),
+        ks::KEY_F6 => Some(17),
+        ks::KEY_F7 => Some(18),
+        ks::KEY_F8 => Some(19),
+        ks::KEY_F9 => Some(20),
+        ks::KEY_F10 => Some(21),
+        ks::KEY_F11 => Some(23),
+        ks::KEY_F12 => Some(24),
+        _ => None,
+    };
+    if let Some(number) = number {
+        let seq = if modified {
+            format!("\x1b[{};{}~", number, modifiers.param())
+        } else {
+            format!("\x1b[{}~", number)
+        };
+        return Some(seq.into_bytes());
+    }
+
+    let bytes: Vec<u8> = match keysym {
+        ks::KEY_F1 => b"\x1bOP".to_vec(),
+        ks::KEY_F2 => b"\x1bOQ".to_vec(),
+        ks::KEY_F3 => b"\x1bOR".to_vec(),
+        ks::KEY_F4 => b"\x1bOS".to_vec(),
+        ks::KEY_ISO_Left_Tab => b"\x1b[Z".to_vec(),
+        ks::KEY_Return | ks::KEY_KP_Enter => b"\r".to_vec(),
+        ks::KEY_BackSpace => b"\x7f".to_vec(),
+        ks::KEY_Tab => b"\t".to_vec(),
+        ks::KEY_Escape => b"\x1b".to_vec(),
+        // NOTE: xkbcommon has applied Ctrl to the text (e.g. Ctrl+W -> 0x17)
+        _ if !text.is_empty() => text.as_bytes().to_vec(),
+        _ => return None,
+    };
+
+    // Alt is sent as the ESC prefix
+    if modifiers.alt {
+        let mut prefixed = vec![0x1b];
+        prefixed.extend(bytes);
+        Some(prefixed)
+    } else {
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONE: Modifiers = Modifiers {
+        shift: false,
+        ctrl: false,
+        alt: false,
+    };
+    const CTRL: Modifiers = Modifiers { ctrl: true, ..NONE };
+    const ALT: Modifiers = Modifiers { alt: true, ..NONE };
+    const CTRL_SHIFT: Modifiers = Modifiers {
+        shift: true,
+        ..CTRL
+    };
+
+    fn encoded(keysym: xkb::Keysym, text: &str, modifiers: Modifiers) -> String {
+        let bytes = encode(keysym, text, modifiers).expect("not encoded");
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn cursor_keys() {
+        assert_eq!(encoded(ks::KEY_Up, "", NONE), "\x1b[A");
+        assert_eq!(encoded(ks::KEY_Left, "", CTRL), "\x1b[1;5D");
+        assert_eq!(encoded(ks::KEY_End, "", CTRL_SHIFT), "\x1b[1;6F");
+    }
+
+    #[test]
+    fn editing_and_function_keys() {
+        assert_eq!(encoded(ks::KEY_Delete, "", NONE), "\x1b[3~");
+        assert_eq!(encoded(ks::KEY_Next, "", ALT), "\x1b[6;3~");
+        assert_eq!(encoded(ks::KEY_F5, "", NONE), "\x1b[15~");
+        assert_eq!(encoded(ks::KEY_F12, "", CTRL), "\x1b[24;5~");
+        assert_eq!(encoded(ks::KEY_F1, "", NONE), "\x1bOP");
+    }
+
+    #[test]
+    fn text_and_control_keys() {
+        assert_eq!(encoded(ks::KEY_Return, "\r", NONE), "\r");
+        assert_eq!(encoded(ks::KEY_BackSpace, "\x08", NONE), "\x7f");
+        assert_eq!(encoded(ks::KEY_ISO_Left_Tab, "", NONE), "\x1b[Z");
+        assert_eq!(encoded(0x61, "a", NONE), "a");
+        assert_eq!(encoded(0x77, "\x17", CTRL), "\x17");
+        assert_eq!(encoded(0xe9, "é", NONE), "é");
+    }
+
+    #[test]
+    fn alt_prefix() {
+        assert_eq!(encoded(0x61, "a", ALT), "\x1ba");
+        assert_eq!(encoded(ks::KEY_BackSpace, "\x08", ALT), "\x1b\x7f");
+    }
+
+    #[test]
+    fn no_text() {
+        assert_eq!(encode(ks::KEY_Multi_key, "", NONE), None);
+    }
+}
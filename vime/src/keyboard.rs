@@ -0,0 +1,39 @@
+use xcb::x::Keysym;
+
+pub const XK_BACKSPACE: Keysym = 0xff08;
+pub const XK_RETURN: Keysym = 0xff0d;
+pub const XK_LEFT: Keysym = 0xff51;
+pub const XK_RIGHT: Keysym = 0xff53;
+
+/// Looks up the keycode which generates the keysym without any modifiers.
+pub fn keysym_to_keycode(conn: &xcb::Connection, keysym: Keysym) -> Option<u8> {
+    let setup = conn.get_setup();
+    let min_keycode = setup.min_keycode();
+    let max_keycode = setup.max_keycode();
+
+    let cookie = conn.send_request(&xcb::x::GetKeyboardMapping {
+        first_keycode: min_keycode,
+        count: max_keycode - min_keycode + 1,
+    });
+    let reply = conn.wait_for_reply(cookie).ok()?;
+
+    let per_keycode = reply.keysyms_per_keycode() as usize;
+    let offset = reply
+        .keysyms()
+        .chunks(per_keycode)
+        .position(|syms| syms.first() == Some(&keysym))?;
+
+    Some(min_keycode + offset as u8)
+}
+
+/// Returns the keycodes bound to the modifiers (Shift, Control, Mod1, etc.).
+pub fn modifier_keycodes(conn: &xcb::Connection) -> Vec<u8> {
+    let cookie = conn.send_request(&xcb::x::GetModifierMapping {});
+    let Ok(reply) = conn.wait_for_reply(cookie) else { return Vec::new() };
+    reply
+        .keycodes()
+        .iter()
+        .copied()
+        .filter(|&keycode| keycode != 0)
+        .collect()
+}
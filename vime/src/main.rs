@@ -1,6 +1,15 @@
 mod backend_passthrough;
 mod backend_vime;
+mod clipboard;
+mod compose;
+mod draft;
+mod expander;
+mod history;
 mod im_server;
+mod key_input;
+mod keyboard;
+mod placement;
+mod status_line;
 
 mod channel {
     use std::sync::mpsc;
@@ -24,11 +33,126 @@ use xcb::x::Window;
 pub enum Message {
     Conn(Arc<xcb::Connection>),
     Window(Window),
-    StartPreedit(xcb_imdkit::Ic),
+    // the upstream IM pinned by the profile of the client
+    StartPreedit(xcb_imdkit::Ic, Option<String>),
+    // the text is opened instead of the draft (to amend the last commit)
+    StartSession(xcb_imdkit::Ic, SessionMode, Client, Option<String>),
+    SessionWindow(xcb_imdkit::Ic, Window),
     CancelPreedit,
-    FocusOut,
-    EditResult(xcb_imdkit::Ic, Option<String>),
+    NextUpstream,
+    CancelSession(xcb_imdkit::Ic),
+    // the IC has been destroyed, so the text of the session can't be committed
+    DropSession(xcb_imdkit::Ic),
+    StartScratchpad,
+    ScratchpadWindow(Window),
+    CancelScratchpad,
+    ScratchpadResult(Option<Commit>),
+    FocusOut(xcb_imdkit::Ic),
+    Pin(xcb_imdkit::Ic, bool),
+    Resized(xcb_imdkit::Ic),
+    FontSize(Window, u32),
+    EditResult(xcb_imdkit::Ic, Option<Commit>),
+    CommitLine(xcb_imdkit::Ic, String),
     ForwardEvent(xcb_imdkit::Ic, xcb_imdkit::KeyEvent),
+    // the preedit string of the upstream IM
+    PreeditStart(xcb_imdkit::Ic),
+    PreeditDraw(xcb_imdkit::Ic, Preedit),
+    PreeditDone(xcb_imdkit::Ic),
+    Input(xcb_imdkit::Ic, Vec<u8>),
+    // a session window has been mapped and painted
+    Exposed(Window),
+    // the output of the command of an expansion, and the grapheme clusters to erase
+    Expanded(xcb_imdkit::Ic, usize, Option<String>),
+}
+
+impl Message {
+    /// Returns the IC which the message is about, if any.
+    pub fn ic(&self) -> Option<&xcb_imdkit::Ic> {
+        match self {
+            Message::StartPreedit(ic, _)
+            | Message::StartSession(ic, ..)
+            | Message::SessionWindow(ic, _)
+            | Message::CancelSession(ic)
+            | Message::DropSession(ic)
+            | Message::FocusOut(ic)
+            | Message::Pin(ic, _)
+            | Message::Resized(ic)
+            | Message::EditResult(ic, _)
+            | Message::CommitLine(ic, _)
+            | Message::ForwardEvent(ic, _)
+            | Message::PreeditStart(ic)
+            | Message::PreeditDraw(ic, _)
+            | Message::PreeditDone(ic)
+            | Message::Input(ic, _)
+            | Message::Expanded(ic, ..) => Some(ic),
+            _ => None,
+        }
+    }
+}
+
+/// Text to be committed to a client.
+pub struct Commit {
+    pub text: String,
+    /// the number of grapheme clusters to move the caret back after committing the text
+    pub caret_back: usize,
+}
+
+impl Commit {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            caret_back: 0,
+        }
+    }
+}
+
+/// A change of the preedit string of the upstream IM, as in XIMPreeditDrawCallbackStruct.
+pub struct Preedit {
+    pub text: String,
+    pub caret: u32,
+    /// the range of the previous string (in characters) replaced with the text
+    pub chg_first: u32,
+    pub chg_length: u32,
+    /// XIM feedback (e.g. underline, reverse) of each character of the text
+    pub feedback: Vec<u32>,
+}
+
+/// The application which a session commits to.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    /// the class name in WM_CLASS
+    pub class: String,
+    pub title: String,
+    /// the top-level window, which has WM_CLASS
+    pub window: Option<Window>,
+}
+
+/// Determines how the text written in a vime session is committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionMode {
+    /// Commits the whole buffer when the editor exits.
+    Normal,
+    /// Commits each line as soon as it is completed.
+    Line,
+}
+
+impl SessionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionMode::Normal => "normal",
+            SessionMode::Line => "line",
+        }
+    }
+
+    /// Returns the mode for the client, which may be overridden by the profile of its class.
+    pub fn for_client(self, client: &Client) -> Self {
+        let profile = vime_config::CONFIG.profile(&client.class);
+        match profile.and_then(|profile| profile.line_mode) {
+            Some(true) => SessionMode::Line,
+            Some(false) => SessionMode::Normal,
+            None => self,
+        }
+    }
 }
 
 pub type Channel = channel::Channel<Message>;
@@ -36,6 +160,11 @@ pub type Channel = channel::Channel<Message>;
 fn main() {
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("history") {
+        std::process::exit(history::command(&args[1..]));
+    }
+
     let (chan_passthru_a, chan_passthru_b) = channel::pair::<Message>();
     let (chan_vime_a, chan_vime_b) = channel::pair::<Message>();
     std::thread::spawn(move || im_server::main(chan_vime_a, chan_passthru_a));
@@ -0,0 +1,198 @@
+use vime_config::Placement;
+
+/// A rectangle in the coordinates of the root window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Self { x, y, w, h }
+    }
+    fn right(&self) -> i32 {
+        self.x + self.w
+    }
+    fn bottom(&self) -> i32 {
+        self.y + self.h
+    }
+    fn contains(&self, (x, y): (i32, i32)) -> bool {
+        self.x <= x && x <= self.right() && self.y <= y && y <= self.bottom()
+    }
+}
+
+/// What the position of the vime window depends on.
+#[derive(Debug, Clone, Default)]
+pub struct Surroundings<'a> {
+    pub monitors: &'a [Rect],
+    /// the position of the caret
+    pub spot: (i32, i32),
+    /// the window of the client which has the caret
+    pub area: Option<Rect>,
+    /// the top-level window of the client
+    pub toplevel: Option<Rect>,
+    pub pointer: (i32, i32),
+}
+
+// distance from the spot to the window placed above it, which is about a line of text
+const LINE_HEIGHT: i32 = 40;
+
+/// Returns the index of the monitor which contains the point, or the first one if none does.
+pub fn monitor_at(monitors: &[Rect], point: (i32, i32)) -> Option<usize> {
+    if monitors.is_empty() {
+        return None;
+    }
+    let i = monitors.iter().position(|m| m.contains(point));
+    Some(i.unwrap_or(0))
+}
+
+/// Calculates the position of the vime window of the size, keeping it inside the monitor.
+/// The caret is used instead of the area or the top-level window if they are unknown.
+pub fn place(placement: Placement, s: &Surroundings, (w, h): (i32, i32)) -> (i32, i32) {
+    let (placement, anchor) = match (placement, s.area, s.toplevel) {
+        (Placement::CoverField, Some(area), _) => (placement, (area.x, area.y)),
+        (Placement::DockBottom, _, Some(toplevel)) => (placement, (toplevel.x, toplevel.bottom())),
+        (Placement::CoverField | Placement::DockBottom, _, _) => (Placement::BelowCaret, s.spot),
+        (Placement::Pointer, _, _) => (placement, s.pointer),
+        _ => (placement, s.spot),
+    };
+
+    let Some(i) = monitor_at(s.monitors, anchor) else { return anchor };
+    let mon = s.monitors[i];
+
+    let (x, mut y) = match placement {
+        Placement::BelowCaret | Placement::Pointer | Placement::CoverField => anchor,
+        Placement::AboveCaret => (anchor.0, anchor.1 - h - LINE_HEIGHT),
+        Placement::Center => (mon.x + (mon.w - w) / 2, mon.y + (mon.h - h) / 2),
+        Placement::DockBottom => (anchor.0, anchor.1 - h),
+    };
+
+    // Flip to the other side of the anchor if there is no room
+    match placement {
+        Placement::BelowCaret if mon.bottom() < y + h => y = anchor.1 - h - LINE_HEIGHT,
+        Placement::Pointer if mon.bottom() < y + h => y = anchor.1 - h,
+        Placement::AboveCaret if y < mon.y => y = anchor.1,
+        _ => {}
+    }
+
+    // Keep the upper-left corner visible even if the window is larger than the monitor
+    let x = x.min(mon.right() - w).max(mon.x);
+    let y = y.min(mon.bottom() - h).max(mon.y);
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONITORS: [Rect; 2] = [
+        Rect {
+            x: 0,
+            y: 0,
+            w: 1920,
+            h: 1080,
+        },
+        Rect {
+            x: 1920,
+            y: 0,
+            w: 1280,
+            h: 1024,
+        },
+    ];
+
+    fn surroundings(spot: (i32, i32)) -> Surroundings<'static> {
+        Surroundings {
+            monitors: &MONITORS,
+            spot,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn monitor_of_point() {
+        assert_eq!(monitor_at(&MONITORS, (100, 100)), Some(0));
+        assert_eq!(monitor_at(&MONITORS, (2000, 100)), Some(1));
+        assert_eq!(monitor_at(&MONITORS, (2000, 1050)), Some(0));
+        assert_eq!(monitor_at(&[], (0, 0)), None);
+    }
+
+    #[test]
+    fn below_caret() {
+        let s = surroundings((100, 200));
+        assert_eq!(place(Placement::BelowCaret, &s, (400, 300)), (100, 200));
+
+        // no room below
+        let s = surroundings((100, 1000));
+        assert_eq!(place(Placement::BelowCaret, &s, (400, 300)), (100, 660));
+    }
+
+    #[test]
+    fn above_caret() {
+        let s = surroundings((100, 500));
+        assert_eq!(place(Placement::AboveCaret, &s, (400, 300)), (100, 160));
+
+        // no room above
+        let s = surroundings((100, 100));
+        assert_eq!(place(Placement::AboveCaret, &s, (400, 300)), (100, 100));
+    }
+
+    #[test]
+    fn clamped_to_monitor() {
+        // overflows the right edge of the second monitor
+        let s = surroundings((3100, 100));
+        assert_eq!(place(Placement::BelowCaret, &s, (400, 300)), (2800, 100));
+
+        // larger than the monitor
+        let s = surroundings((100, 100));
+        assert_eq!(place(Placement::BelowCaret, &s, (2000, 1200)), (0, 0));
+    }
+
+    #[test]
+    fn cover_field() {
+        let s = Surroundings {
+            area: Some(Rect::new(300, 400, 800, 200)),
+            ..surroundings((350, 450))
+        };
+        assert_eq!(place(Placement::CoverField, &s, (400, 300)), (300, 400));
+
+        // unknown area
+        let s = surroundings((350, 450));
+        assert_eq!(place(Placement::CoverField, &s, (400, 300)), (350, 450));
+    }
+
+    #[test]
+    fn center() {
+        let s = surroundings((2000, 100));
+        assert_eq!(place(Placement::Center, &s, (400, 300)), (2360, 362));
+    }
+
+    #[test]
+    fn pointer() {
+        let s = Surroundings {
+            pointer: (500, 1000),
+            ..surroundings((100, 100))
+        };
+        assert_eq!(place(Placement::Pointer, &s, (400, 300)), (500, 700));
+    }
+
+    #[test]
+    fn dock_bottom() {
+        let s = Surroundings {
+            toplevel: Some(Rect::new(100, 100, 1000, 800)),
+            ..surroundings((300, 300))
+        };
+        assert_eq!(place(Placement::DockBottom, &s, (400, 300)), (100, 600));
+    }
+
+    #[test]
+    fn no_monitor() {
+        let s = Surroundings {
+            spot: (100, 200),
+            ..Default::default()
+        };
+        assert_eq!(place(Placement::Center, &s, (400, 300)), (100, 200));
+    }
+}
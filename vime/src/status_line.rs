@@ -0,0 +1,145 @@
+use xcb::x::{Font, Gcontext, Window};
+
+/// A row below the terminal which shows the state of a session.
+/// It is a child window of the vime window drawn with an X core font.
+pub struct StatusLine {
+    window: Window,
+    font: Font,
+    gc: Gcontext,
+    ascent: i16,
+    height: u16,
+}
+
+const PADDING: u16 = 2;
+
+impl StatusLine {
+    pub fn new(conn: &xcb::Connection, parent: Window) -> Self {
+        let config = &vime_config::CONFIG;
+
+        // Fall back to the default font if no font of the size is found
+        let font = conn.generate_id();
+        let pattern = format!(
+            "-*-fixed-medium-r-*--{}-*-*-*-*-*-iso10646-1",
+            config.status_bar_font_size
+        );
+        let cookie = conn.send_request_checked(&xcb::x::OpenFont {
+            fid: font,
+            name: pattern.as_bytes(),
+        });
+        if conn.check_request(cookie).is_err() {
+            log::warn!("status line: no font matches {}", pattern);
+            conn.send_request(&xcb::x::OpenFont {
+                fid: font,
+                name: b"fixed",
+            });
+        }
+
+        let cookie = conn.send_request(&xcb::x::QueryFont {
+            font: xcb::x::Fontable::Font(font),
+        });
+        let reply = conn.wait_for_reply(cookie).unwrap();
+        let ascent = reply.font_ascent();
+        let height = (reply.font_ascent() + reply.font_descent()) as u16 + PADDING * 2;
+
+        // RRGGBBAA -> ARGB
+        let fg = 0xFF000000 | (config.color_white >> 8);
+        let bg = 0xFF000000 | (config.color_bright_black >> 8);
+
+        let window = conn.generate_id();
+        conn.send_request(&xcb::x::CreateWindow {
+            depth: xcb::x::COPY_FROM_PARENT as u8,
+            wid: window,
+            parent,
+            x: 0,
+            y: 0,
+            width: 1,
+            height,
+            border_width: 0,
+            class: xcb::x::WindowClass::InputOutput,
+            visual: xcb::x::COPY_FROM_PARENT,
+            value_list: &[xcb::x::Cw::BackPixel(bg), xcb::x::Cw::BorderPixel(bg)],
+        });
+
+        let gc = conn.generate_id();
+        conn.send_request(&xcb::x::CreateGc {
+            cid: gc,
+            drawable: xcb::x::Drawable::Window(window),
+            value_list: &[
+                xcb::x::Gc::Foreground(fg),
+                xcb::x::Gc::Background(bg),
+                xcb::x::Gc::Font(font),
+            ],
+        });
+
+        conn.send_request(&xcb::x::MapWindow { window });
+        conn.flush().unwrap();
+
+        Self {
+            window,
+            font,
+            gc,
+            ascent,
+            height,
+        }
+    }
+
+    /// The height of the status line in pixels.
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Places the status line below the terminal area.
+    pub fn place(&self, conn: &xcb::Connection, y: u32, width: u32) {
+        conn.send_request(&xcb::x::ConfigureWindow {
+            window: self.window,
+            value_list: &[
+                xcb::x::ConfigWindow::X(0),
+                xcb::x::ConfigWindow::Y(y as i32),
+                xcb::x::ConfigWindow::Width(width.max(1)),
+            ],
+        });
+        conn.flush().unwrap();
+    }
+
+    pub fn draw(&self, conn: &xcb::Connection, text: &str) {
+        conn.send_request(&xcb::x::ClearArea {
+            exposures: false,
+            window: self.window,
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        });
+
+        // NOTE: only the BMP is supported, and at most 255 characters are drawn
+        let string: Vec<xcb::x::Char2b> = text
+            .chars()
+            .map(|ch| {
+                let code = u16::try_from(ch as u32).unwrap_or(b'?' as u16);
+                xcb::x::Char2b {
+                    byte1: (code >> 8) as u8,
+                    byte2: code as u8,
+                }
+            })
+            .take(255)
+            .collect();
+
+        conn.send_request(&xcb::x::ImageText16 {
+            drawable: xcb::x::Drawable::Window(self.window),
+            gc: self.gc,
+            x: PADDING as i16,
+            y: PADDING as i16 + self.ascent,
+            string: &string,
+        });
+        conn.flush().unwrap();
+    }
+
+    pub fn destroy(self, conn: &xcb::Connection) {
+        conn.send_request(&xcb::x::FreeGc { gc: self.gc });
+        conn.send_request(&xcb::x::CloseFont { font: self.font });
+        conn.send_request(&xcb::x::DestroyWindow {
+            window: self.window,
+        });
+        conn.flush().unwrap();
+    }
+}
@@ -15,9 +15,13 @@ fn vime_config() -> Config {
         );
     }
 
+    // NOTE: Each session has its own buffer file, which is passed via $VIME_BUFFER_FILE
+    let mut cmd: Vec<String> = ["/bin/sh", "-c", r#"exec "$@" "$VIME_BUFFER_FILE""#, "vime"]
+        .into_iter()
+        .map(|s| s.to_owned())
+        .collect();
     let editor = std::env::var("VIME_EDITOR").unwrap();
-    let mut cmd: Vec<String> = editor.split(' ').map(|s| s.to_owned()).collect();
-    cmd.push("/tmp/vime_buffer.txt".to_owned());
+    cmd.extend(editor.split(' ').map(|s| s.to_owned()));
 
     let mut config = build();
     config.shell = cmd;
@@ -29,6 +33,7 @@ fn find_vim() -> std::path::PathBuf {
     std::path::PathBuf::from("/usr/bin/vim")
 }
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -36,9 +41,100 @@ pub struct Config {
     pub default_columns: usize,
     pub default_rows: usize,
 
+    // limits for the resize requests from the editor (XTWINOPS)
+    pub accept_resize_request: bool,
+    pub min_columns: usize,
+    pub min_rows: usize,
+    pub max_columns: usize,
+    pub max_rows: usize,
+
     pub trigger_key_state: u32,
     pub trigger_key_keycode: u8,
 
+    // trigger of the line-by-line mode (keycode 0 disables it)
+    pub line_mode_trigger_key_state: u32,
+    pub line_mode_trigger_key_keycode: u8,
+    // whether to send Return after committing each line
+    pub line_mode_send_return: bool,
+
+    // key to pin the session so that it stays open across focus changes (keycode 0 disables it)
+    pub pin_key_state: u32,
+    pub pin_key_keycode: u8,
+    // whether to commit the text of a pinned session to the focused IC instead of the original one
+    pub pin_commit_to_focused: bool,
+    // RRGGBB
+    pub pinned_border_color: u32,
+
+    // keys to reopen the last commit in the editor and replace it, or to delete it
+    // (keycode 0 disables them)
+    pub amend_key_state: u32,
+    pub amend_key_keycode: u8,
+    pub undo_key_state: u32,
+    pub undo_key_keycode: u8,
+
+    // whether to keep the committed texts in `$XDG_DATA_HOME/vime/history.jsonl`
+    pub history: bool,
+    pub history_max_entries: usize,
+    // days to keep the entries (0 keeps them forever)
+    pub history_max_days: u64,
+    // class names in WM_CLASS of the applications whose texts are not kept (case-insensitive)
+    #[serde(default)]
+    pub history_exclude: Vec<String>,
+    // key to open the entry picked by `vime history pick`, or the last one for the application
+    // (keycode 0 disables it)
+    pub recall_key_state: u32,
+    pub recall_key_keycode: u8,
+
+    // XIM servers to pass the keys through to (e.g. ["fcitx", "ibus"]) instead of $XMODIFIERS
+    #[serde(default)]
+    pub upstream_ims: Vec<String>,
+    // key to switch to the next upstream IM (keycode 0 disables it)
+    pub upstream_im_key_state: u32,
+    pub upstream_im_key_keycode: u8,
+    // whether to let the clients draw the preedit string of the upstream IM (on-the-spot style),
+    // which some clients prefer to telling the caret position
+    pub passthrough_preedit: bool,
+    // abbreviations expanded in the passthrough mode (profiles can add their own)
+    #[serde(default)]
+    pub expansions: Vec<Expansion>,
+
+    // global key to open a scratchpad, whose text goes to the clipboard (keycode 0 disables it)
+    pub scratchpad_key_state: u32,
+    pub scratchpad_key_keycode: u8,
+
+    // whether to move the caret in the client to where the cursor of the editor was
+    pub restore_cursor: bool,
+
+    // whether to show the target client, the mode, etc. below the terminal
+    pub status_line: bool,
+
+    // where to put the vime window
+    pub placement: Placement,
+    // whether to bypass the window manager (false lets it manage and place the vime window)
+    pub override_redirect: bool,
+
+    // frame of the vime window (can be overridden by profiles)
+    pub border_width: u32,
+    // RRGGBB
+    pub border_color: u32,
+    // pixels around the terminal
+    pub padding: u32,
+    // 0.0 (transparent) - 1.0 (opaque), which needs a compositor
+    pub opacity: f64,
+
+    // settings for each client, keyed by the class name in WM_CLASS (case-insensitive)
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+
+    // whether to scale `font_size` by the DPI of the monitor where the window is
+    pub dpi_scaling: bool,
+    // DPI for which `font_size` is specified
+    pub base_dpi: u32,
+
+    // seconds before a hidden session is closed (0 disables the timeout)
+    pub idle_timeout: u64,
+    pub idle_timeout_action: IdleTimeoutAction,
+
     ///////////////////////////// toyterm ////////////////////////////
     pub shell: Vec<String>,
 
@@ -75,6 +171,97 @@ pub struct Config {
     pub east_asian_width_ambiguous: u8,
 }
 
+/// What to do with a session when it reaches the idle timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleTimeoutAction {
+    /// Discards the text.
+    Cancel,
+    /// Saves the text as a draft.
+    Save,
+}
+
+/// Where to put the vime window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// Below the caret, or above it if there is no room.
+    BelowCaret,
+    /// Above the caret, or below it if there is no room.
+    AboveCaret,
+    /// Over the window of the client which has the caret.
+    CoverField,
+    /// At the center of the monitor.
+    Center,
+    /// At the mouse pointer.
+    Pointer,
+    /// At the bottom of the top-level window of the client.
+    DockBottom,
+}
+
+/// Settings which override the global ones for the clients of a class.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub placement: Option<Placement>,
+    pub border_width: Option<u32>,
+    pub border_color: Option<u32>,
+    pub padding: Option<u32>,
+    pub opacity: Option<f64>,
+    // whether a session is always (or never) in the line-by-line mode regardless of the trigger
+    pub line_mode: Option<bool>,
+    // one of `upstream_ims` used for the clients regardless of the key to switch them
+    pub upstream_im: Option<String>,
+    // abbreviations only for the clients, which take precedence over the global ones
+    pub expansions: Vec<Expansion>,
+}
+
+/// An abbreviation replaced with a text when typed in the passthrough mode.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Expansion {
+    pub trigger: String,
+    pub text: String,
+    // command whose stdout (without the trailing newlines) is used instead of `text`
+    pub command: Vec<String>,
+}
+
+/// Appearance of the vime window frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub border_width: u32,
+    pub border_color: u32,
+    pub padding: u32,
+    pub opacity: f64,
+}
+
+impl Config {
+    /// Returns the profile for the class name in WM_CLASS, which is matched case-insensitively.
+    pub fn profile(&self, class: &str) -> Option<&Profile> {
+        self.profiles
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(class))
+            .map(|(_, profile)| profile)
+    }
+
+    /// Returns the style for the class name in WM_CLASS, overridden by its profile if any.
+    pub fn style(&self, class: &str) -> Style {
+        let profile = self.profile(class).cloned().unwrap_or_default();
+        Style {
+            border_width: profile.border_width.unwrap_or(self.border_width),
+            border_color: profile.border_color.unwrap_or(self.border_color),
+            padding: profile.padding.unwrap_or(self.padding),
+            opacity: profile.opacity.unwrap_or(self.opacity).clamp(0.0, 1.0),
+        }
+    }
+
+    /// Returns the expansions for the class name in WM_CLASS, those of its profile first.
+    pub fn expansions<'a>(&'a self, class: &str) -> impl Iterator<Item = &'a Expansion> {
+        let profile = self.profile(class).map(|profile| profile.expansions.iter());
+        profile.into_iter().flatten().chain(self.expansions.iter())
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         let shell = vec![std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned())];
@@ -83,9 +270,64 @@ impl Default for Config {
             default_columns: 80,
             default_rows: 24,
 
+            accept_resize_request: true,
+            min_columns: 10,
+            min_rows: 1,
+            max_columns: 200,
+            max_rows: 60,
+
             trigger_key_state: 0x8,  // Alt
             trigger_key_keycode: 62, // RightShift
 
+            line_mode_trigger_key_state: 0x8 | 0x4, // Alt+Ctrl
+            line_mode_trigger_key_keycode: 0,
+            line_mode_send_return: true,
+
+            pin_key_state: 0x8 | 0x4, // Alt+Ctrl
+            pin_key_keycode: 0,
+            pin_commit_to_focused: false,
+            pinned_border_color: 0xFF8000,
+
+            amend_key_state: 0x8 | 0x4, // Alt+Ctrl
+            amend_key_keycode: 0,
+            undo_key_state: 0x8 | 0x4, // Alt+Ctrl
+            undo_key_keycode: 0,
+
+            history: false,
+            history_max_entries: 1000,
+            history_max_days: 90,
+            history_exclude: Vec::new(),
+            recall_key_state: 0x8 | 0x4, // Alt+Ctrl
+            recall_key_keycode: 0,
+
+            upstream_ims: Vec::new(),
+            upstream_im_key_state: 0x8 | 0x4, // Alt+Ctrl
+            upstream_im_key_keycode: 0,
+            passthrough_preedit: false,
+            expansions: Vec::new(),
+
+            scratchpad_key_state: 0x8 | 0x4, // Alt+Ctrl
+            scratchpad_key_keycode: 0,
+
+            restore_cursor: true,
+
+            status_line: false,
+
+            placement: Placement::BelowCaret,
+            override_redirect: true,
+
+            border_width: 1,
+            border_color: 0x000000,
+            padding: 0,
+            opacity: 1.0,
+            profiles: HashMap::new(),
+
+            dpi_scaling: false,
+            base_dpi: 96,
+
+            idle_timeout: 0,
+            idle_timeout_action: IdleTimeoutAction::Save,
+
             shell,
 
             east_asian_width_ambiguous: 1,
@@ -125,6 +367,15 @@ impl Default for Config {
 }
 
 fn build() -> Config {
+    let user_source = find_config_file().map(|path| config::File::from(path).required(false));
+    build_with(user_source)
+}
+
+/// Builds the config from the default one overridden by the user config.
+fn build_with<S>(user_source: Option<S>) -> Config
+where
+    S: ::config::Source + Send + Sync + 'static,
+{
     let mut builder = ::config::Config::builder();
 
     // default config
@@ -133,8 +384,8 @@ fn build() -> Config {
     builder = builder.add_source(default_source);
 
     // user config
-    if let Some(config_path) = find_config_file() {
-        builder = builder.add_source(config::File::from(config_path).required(false));
+    if let Some(user_source) = user_source {
+        builder = builder.add_source(user_source);
     }
 
     builder
@@ -144,6 +395,32 @@ fn build() -> Config {
         .expect("Failed to build config")
 }
 
+/// Returns the directory to store the data of vime (`$XDG_DATA_HOME/vime`).
+pub fn data_dir() -> Option<PathBuf> {
+    let mut xdg_data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            // fallback to "$HOME/.local/share"
+            let home = std::env::var_os("HOME")?;
+            let mut p = PathBuf::from(home);
+            p.push(".local");
+            p.push("share");
+            Some(p)
+        })?;
+
+    xdg_data_home.push("vime");
+    Some(xdg_data_home)
+}
+
+/// Returns the directory for the files shared with the editor while a session is running
+/// (`$XDG_RUNTIME_DIR/vime`, or `run` in `data_dir()` if `$XDG_RUNTIME_DIR` is not set).
+pub fn runtime_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(xdg_runtime_dir) => Some(PathBuf::from(xdg_runtime_dir).join("vime")),
+        None => Some(data_dir()?.join("run")),
+    }
+}
+
 fn find_config_file() -> Option<PathBuf> {
     let mut xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
         .map(PathBuf::from)
@@ -159,3 +436,23 @@ fn find_config_file() -> Option<PathBuf> {
     xdg_config_home.push("config.toml");
     Some(xdg_config_home)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_with_capitalized_class() {
+        let toml = r#"
+            [profiles.Firefox]
+            placement = "dock_bottom"
+        "#;
+        let config = build_with(Some(config::File::from_str(toml, config::FileFormat::Toml)));
+
+        for class in ["Firefox", "firefox", "FIREFOX"] {
+            let profile = config.profile(class).expect("profile not found");
+            assert_eq!(profile.placement, Some(Placement::DockBottom));
+        }
+        assert!(config.profile("Chromium").is_none());
+    }
+}